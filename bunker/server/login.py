@@ -21,7 +21,7 @@ challenge = base64.b64decode(claims["chal"])
 
 with connect("ws://127.0.0.1:8000") as ws:
 	msg = msgpack.packb({
-		"Sign": [
+		"Authenticate": [
 			"example.com",
 			list(challenge),
 			False
@@ -18,15 +18,20 @@ challenge = get_random_bytes(32)
 h = SHA256.new(challenge)
 
 with connect("ws://127.0.0.1:8000") as ws:
-	msg = msgpack.packb({
-		"Sign": [
-			"example.com",
-			list(challenge),
-			True
-		]
-	})
-	ws.send(msg)
-	resp = msgpack.unpackb(ws.recv())
+	def sign(kind):
+		ws.send(msgpack.packb({
+			kind: [
+				"example.com",
+				list(challenge),
+				True
+			]
+		}))
+		return msgpack.unpackb(ws.recv())
+
+	resp = sign("Authenticate")
+	if "Error" in resp and resp["Error"][0] == "KeyNotFound":
+		resp = sign("Register")
+
 	resp = {
 		"signed_data": {
 			"r": bytes(resp["Sign"][0]),
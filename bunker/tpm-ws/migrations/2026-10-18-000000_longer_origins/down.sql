@@ -0,0 +1,33 @@
+/*
+Copyright James Connolly 2024
+
+This file is part of tpm-ws.
+
+tpm-ws is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+
+tpm-ws is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with tpm-ws. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+-- keys for origins that don't fit the old limit are lost
+CREATE TABLE "tpm_keys_old" (
+	id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+	origin TEXT UNIQUE NOT NULL CHECK (length(origin) <= 50),
+	sealed_private_key BLOB NOT NULL,
+	public_key BLOB NOT NULL
+);
+INSERT INTO "tpm_keys_old" SELECT id, origin, sealed_private_key, public_key FROM "tpm_keys" WHERE length(origin) <= 50;
+DROP TABLE "tpm_keys";
+ALTER TABLE "tpm_keys_old" RENAME TO "tpm_keys";
+
+CREATE TABLE "software_keys_old" (
+	id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+	origin TEXT UNIQUE NOT NULL CHECK (length(origin) <= 50),
+	encrypted_private_key BLOB NOT NULL,
+	encrypted_private_key_iv BLOB NOT NULL,
+	private_key_sha3_512_sum BLOB NOT NULL
+);
+INSERT INTO "software_keys_old" SELECT id, origin, encrypted_private_key, encrypted_private_key_iv, private_key_sha3_512_sum FROM "software_keys" WHERE length(origin) <= 50;
+DROP TABLE "software_keys";
+ALTER TABLE "software_keys_old" RENAME TO "software_keys";
@@ -0,0 +1,21 @@
+/*
+Copyright James Connolly 2024
+
+This file is part of tpm-ws.
+
+tpm-ws is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+
+tpm-ws is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with tpm-ws. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+-- `grantee` is whoever the user said yes to, `expires_at` is NULL for "always"
+CREATE TABLE "consent_grants" (
+	id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+	origin TEXT NOT NULL CHECK (length(origin) <= 300),
+	grantee TEXT NOT NULL CHECK (length(grantee) <= 400),
+	expires_at BIGINT,
+	created_at BIGINT NOT NULL,
+	UNIQUE (origin, grantee)
+);
@@ -0,0 +1,23 @@
+/*
+Copyright James Connolly 2024
+
+This file is part of tpm-ws.
+
+tpm-ws is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+
+tpm-ws is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with tpm-ws. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+-- the local ca and the localhost certificate it issued, private keys encrypted like `software_keys`
+CREATE TABLE "tls_certificates" (
+	id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+	name TEXT NOT NULL UNIQUE CHECK (name IN ('ca', 'leaf')),
+	certificate BLOB NOT NULL,
+	encrypted_private_key BLOB NOT NULL,
+	encrypted_private_key_iv BLOB NOT NULL,
+	private_key_sha3_512_sum BLOB NOT NULL,
+	expires_at BIGINT NOT NULL,
+	created_at BIGINT NOT NULL
+);
@@ -0,0 +1,210 @@
+/*
+Copyright James Connolly 2024
+
+This file is part of tpm-ws.
+
+tpm-ws is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+
+tpm-ws is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with tpm-ws. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use serde::Deserialize;
+use std::io::IsTerminal;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+// lives next to db.sqlite
+const CONFIG_PATH: &str = "config.toml";
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+// a missing file or key means the default
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+	// lets anything that isn't a web page, i.e. anything that didn't send an `Origin` header, use keys for any origin
+	// that means every local user, as anyone can connect without one
+	pub allow_non_browser: bool,
+	// local apps always have to pair unless allow_non_browser is set, web pages are already kept to their own origin so only have to if this is
+	pub require_pairing: bool,
+	pub consent: ConsentConfig,
+	pub policy: PolicyConfig,
+	pub rate_limit: RateLimitConfig,
+	pub unix_socket: UnixSocketConfig,
+	pub tls: TlsConfig
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConsentConfig {
+	pub method: ConsentMethod,
+	// how long the user has to answer before it counts as a no
+	pub timeout_secs: u64,
+	// how long "remember" remembers a yes to signing in for
+	pub remember_minutes: u64,
+	// program and arguments for `ConsentMethod::Command`
+	pub command: Vec<String>
+}
+
+impl Default for ConsentConfig {
+	fn default() -> Self {
+		ConsentConfig {
+			method: ConsentMethod::default(),
+			timeout_secs: 60,
+			remember_minutes: 15,
+			command: Vec::new()
+		}
+	}
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ConsentMethod {
+	// asks at the terminal tpm-ws runs in
+	#[default]
+	Terminal,
+	// a desktop notification with allow and deny buttons
+	Notification,
+	// runs `command`, which allows by exiting successfully and denies by failing or printing "deny"
+	Command,
+	// never asks, which allows everything
+	None
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+	// serves wss:// on this port as well as ws:// on the usual one, not at all if left out
+	pub port: Option<u16>
+}
+
+// for local apps, which the kernel can tell us about, unlike on tcp where any local user can connect
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct UnixSocketConfig {
+	// where to listen, not at all if left out
+	pub path: Option<PathBuf>,
+	// of the socket file
+	pub mode: u32,
+	// users that may connect, only the one tpm-ws runs as if left out
+	pub uids: Option<Vec<u32>>,
+	// lets apps on the socket use keys without pairing, even with require_pairing, as the kernel vouches for them
+	pub trusted: bool
+}
+
+impl Default for UnixSocketConfig {
+	fn default() -> Self {
+		UnixSocketConfig {
+			path: None,
+			mode: 0o600,
+			uids: None,
+			trusted: false
+		}
+	}
+}
+
+// how often signing requests can be made, however many of them the user would agree to
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+	// for each connection
+	pub connection: Limit,
+	// for each origin, across every connection
+	pub origin: Limit
+}
+
+impl Default for RateLimitConfig {
+	fn default() -> Self {
+		RateLimitConfig {
+			connection: Limit { per_minute: 120, burst: 20 },
+			origin: Limit { per_minute: 60, burst: 10 }
+		}
+	}
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct Limit {
+	// the rate that can be kept up, 0 for no limit at all
+	pub per_minute: u32,
+	// how many can be made at once after a quiet spell
+	pub burst: u32
+}
+
+// checked on top of everything else, see policy.rs
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct PolicyConfig {
+	// whether origins that no rule matches can be used at all
+	pub default: PolicyDefault,
+	// origins nobody may use, whatever the rules say
+	pub deny: Vec<String>,
+	// the first one whose `origins` match is the one that applies
+	#[serde(rename = "rule")]
+	pub rules: Vec<PolicyRule>
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PolicyDefault {
+	#[default]
+	Allow,
+	Deny
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyRule {
+	// origins as in requests, and `*.example.com` for every subdomain of example.com
+	pub origins: Vec<String>,
+	// "page", "local", "paired" or "paired:<name>", anyone if left out
+	#[serde(default)]
+	pub clients: Option<Vec<String>>,
+	// asks every time, and refuses if there's no way to ask
+	#[serde(default)]
+	pub require_consent: bool,
+	// any supported curve if left out
+	#[serde(default)]
+	pub curves: Option<Vec<String>>,
+	#[serde(default)]
+	pub max_signatures_per_minute: Option<u32>
+}
+
+// a config that doesn't parse is a mistake worth stopping for, not something to quietly ignore
+pub fn load() {
+	let config = match std::fs::read_to_string(CONFIG_PATH) {
+		Ok(contents) => toml::from_str(&contents).unwrap_or_else(|e| panic!("invalid {CONFIG_PATH}: {e}")),
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => Config::default(),
+		Err(e) => panic!("failed to read {CONFIG_PATH}: {e}")
+	};
+
+	if config.consent.method == ConsentMethod::Command && config.consent.command.is_empty() {
+		panic!("consent.method is \"command\", but consent.command is empty");
+	}
+	if config.consent.method == ConsentMethod::Terminal && !std::io::stdin().is_terminal() {
+		panic!("consent.method is \"terminal\", but stdin isn't a terminal");
+	}
+
+	for (name, limit) in [("connection", config.rate_limit.connection), ("origin", config.rate_limit.origin)] {
+		if limit.per_minute > 0 && limit.burst == 0 {
+			panic!("rate_limit.{name}.burst is 0, which would refuse everything, set per_minute to 0 instead to turn the limit off");
+		}
+	}
+
+	if config.tls.port == Some(crate::PORT) {
+		panic!("tls.port can't be {}, ws:// is already served there", crate::PORT);
+	}
+
+	if cfg!(not(unix)) && config.unix_socket.path.is_some() {
+		panic!("unix_socket.path is set, but there are no unix sockets here");
+	}
+
+	log::debug!("loaded {config:?}");
+	CONFIG.set(config).expect("config loaded twice");
+}
+
+pub fn get() -> &'static Config {
+	CONFIG.get().expect("config used before it was loaded")
+}
@@ -0,0 +1,503 @@
+/*
+Copyright James Connolly 2024
+
+This file is part of tpm-ws.
+
+tpm-ws is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+
+tpm-ws is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with tpm-ws. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::io::{BufRead, IsTerminal, Write};
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::sync::{mpsc, Mutex};
+use tokio::io::AsyncWriteExt;
+use tokio::task::spawn_blocking;
+use tokio::time::timeout;
+use serde::Serialize;
+use diesel::{QueryDsl, RunQueryDsl, ExpressionMethods, OptionalExtension, BoolExpressionMethods};
+use crate::config::{self, ConsentMethod};
+use crate::db;
+use crate::error::{Error, ErrorCode};
+use crate::unix::Peer;
+
+// only one question can sensibly be in front of the user at a time
+static PROMPT_LOCK: Mutex<()> = Mutex::const_new(());
+
+// one thread owns stdin for good, since a read can't be abandoned once its prompt times out
+static STDIN_LINES: Mutex<Option<mpsc::UnboundedReceiver<String>>> = Mutex::const_new(None);
+
+#[derive(Debug, Clone, Copy)]
+pub enum Action {
+	Register,
+	Authenticate,
+	Delete
+}
+
+impl Action {
+	fn describe(self) -> &'static str {
+		match self {
+			Action::Register => "create a new key for",
+			Action::Authenticate => "sign in with your key for",
+			Action::Delete => "delete your key for"
+		}
+	}
+
+	// deleting and making keys is rare enough, and serious enough, to ask every time
+	fn rememberable(self) -> bool {
+		matches!(self, Action::Authenticate)
+	}
+
+	fn name(self) -> &'static str {
+		match self {
+			Action::Register => "register",
+			Action::Authenticate => "authenticate",
+			Action::Delete => "delete"
+		}
+	}
+}
+
+#[derive(Debug)]
+pub struct Prompt {
+	// as it should be shown to the user
+	pub client: String,
+	// what a remembered yes is stored under, `None` for clients that get asked every time
+	pub grantee: Option<String>,
+	pub origin: String,
+	pub action: Action,
+	// what the client says is being signed, shown as is
+	pub display: Option<String>,
+	// of exactly what would be signed, `None` when nothing is
+	pub data_sha256: Option<[u8; 32]>,
+	// asked every time and refused if nobody can be asked, for deletes and by policy
+	pub required: bool,
+	// only for connections over the unix socket
+	pub peer: Option<Peer>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Answer {
+	No,
+	Once,
+	// for `remember_minutes`
+	Remember,
+	Always
+}
+
+impl Prompt {
+	fn question(&self) -> String {
+		let question = format!("{} wants to {} {}", self.client, self.action.describe(), self.origin);
+		match &self.display {
+			// the client picks the text, so escape it rather than let it write to the terminal
+			Some(display) => format!("{question} to confirm {display:?}"),
+			None => question
+		}
+	}
+
+	// a yes to one text isn't a yes to whatever text comes next
+	fn rememberable(&self) -> bool {
+		self.grantee.is_some() && self.action.rememberable() && self.display.is_none() && !self.required
+	}
+}
+
+// `Ok` only if the user said yes, now or in a way that was remembered, however the config says to ask them
+pub async fn ask(prompt: &Prompt) -> Result<(), Error> {
+	let config = &config::get().consent;
+	if config.method == ConsentMethod::None && prompt.required {
+		log::error!("consent is required to {} {}, but consent.method is none", prompt.action.name(), prompt.origin);
+		return Err(Error::new(ErrorCode::ConsentDenied, "this needs the user to say yes, but there's no way to ask them"));
+	}
+	if config.method == ConsentMethod::None || granted(prompt).await? {
+		return Ok(());
+	}
+
+	let _guard = PROMPT_LOCK.lock().await;
+	// again, since pipelined requests for the same origin will all have queued up behind the first one's prompt
+	if granted(prompt).await? {
+		return Ok(());
+	}
+
+	let question = prompt.question();
+	log::info!("asking: {question}");
+
+	let answer = timeout(Duration::from_secs(config.timeout_secs), async {
+		match config.method {
+			ConsentMethod::Terminal => terminal(&question, prompt.rememberable()).await,
+			ConsentMethod::Notification => notification(&question, prompt.rememberable()).await,
+			ConsentMethod::Command => command(&config.command, prompt).await,
+			ConsentMethod::None => Ok(Answer::Once)
+		}
+	}).await;
+
+	match answer {
+		Ok(Ok(Answer::No)) => Err(Error::new(ErrorCode::ConsentDenied, "the user said no")),
+		Ok(Ok(answer)) => {
+			// the user did say yes, so this request goes ahead either way
+			if let Err(e) = remember(prompt, answer).await {
+				log::error!("failed to remember consent for {}: {e}", prompt.origin);
+			}
+			Ok(())
+		},
+		// nobody got asked, which is as good as a no
+		Ok(Err(e)) => {
+			log::error!("failed to ask \"{question}\": {e}");
+			Err(Error::new(ErrorCode::ConsentDenied, format!("couldn't ask the user: {}", e.message)))
+		},
+		Err(_) => {
+			log::warn!("no answer to \"{question}\" in time");
+			if config.method == ConsentMethod::Terminal {
+				eprintln!("too late, assuming no");
+			}
+			Err(Error::new(ErrorCode::ConsentTimeout, "the user didn't answer in time"))
+		}
+	}
+}
+
+// forgets every remembered yes for the origin, whoever it was for
+pub async fn revoke(origin: String) -> Result<(), Error> {
+	spawn_blocking(move || {
+		use crate::schema::consent_grants::dsl;
+
+		let mut conn = db::get_conn()?;
+		let revoked = diesel::delete(dsl::consent_grants.filter(dsl::origin.eq(&origin))).execute(&mut conn)?;
+		log::info!("revoked {revoked} consent grants for {origin}");
+		Ok(())
+	}).await?
+}
+
+async fn granted(prompt: &Prompt) -> Result<bool, Error> {
+	// a command is asked every time, so a yes remembered before it was configured mustn't get around it
+	if config::get().consent.method == ConsentMethod::Command {
+		return Ok(false);
+	}
+
+	let (origin, grantee) = match &prompt.grantee {
+		Some(grantee) if prompt.rememberable() => (prompt.origin.clone(), grantee.clone()),
+		_ => return Ok(false)
+	};
+
+	spawn_blocking(move || {
+		use crate::schema::consent_grants::dsl;
+
+		let mut conn = db::get_conn()?;
+		let grant = dsl::consent_grants.filter(dsl::origin.eq(&origin).and(dsl::grantee.eq(&grantee)))
+			.select(dsl::expires_at).first::<Option<i64>>(&mut conn).optional()?;
+
+		match grant {
+			Some(expires_at) if is_live(expires_at, db::now()) => Ok(true),
+			Some(_) => {
+				diesel::delete(dsl::consent_grants.filter(dsl::origin.eq(&origin).and(dsl::grantee.eq(&grantee)))).execute(&mut conn)?;
+				Ok(false)
+			},
+			None => Ok(false)
+		}
+	}).await?
+}
+
+// whether a grant expiring at `expires_at` still counts at `now`
+fn is_live(expires_at: Option<i64>, now: i64) -> bool {
+	match expires_at {
+		Some(expires_at) => expires_at > now,
+		None => true
+	}
+}
+
+async fn remember(prompt: &Prompt, answer: Answer) -> Result<(), Error> {
+	let now = db::now();
+	let expires_at = match answer {
+		Answer::No | Answer::Once => return Ok(()),
+		Answer::Remember => Some(now + 60 * config::get().consent.remember_minutes as i64),
+		Answer::Always => None
+	};
+
+	let Some(grantee) = prompt.grantee.clone() else {
+		return Ok(());
+	};
+
+	let grant = crate::models::NewConsentGrant {
+		origin: prompt.origin.clone(),
+		grantee,
+		expires_at,
+		created_at: now
+	};
+
+	spawn_blocking(move || {
+		use crate::schema::consent_grants::dsl;
+
+		let mut conn = db::get_conn()?;
+		diesel::insert_into(dsl::consent_grants).values(&grant)
+			.on_conflict((dsl::origin, dsl::grantee)).do_update().set(&grant)
+			.execute(&mut conn)?;
+		Ok(())
+	}).await?
+}
+
+async fn terminal(question: &str, rememberable: bool) -> Result<Answer, Error> {
+	if !std::io::stdin().is_terminal() {
+		return Err(Error::new(ErrorCode::ConsentDenied, "there is no terminal to ask the user on"));
+	}
+
+	let mut lines = STDIN_LINES.lock().await;
+	let lines = lines.get_or_insert_with(read_stdin);
+
+	// whatever was typed while nobody was asking isn't an answer to this
+	while lines.try_recv().is_ok() {}
+
+	if rememberable {
+		let minutes = config::get().consent.remember_minutes;
+		eprint!("{question}, allow? [y]es, [r]emember for {minutes} minutes, [a]lways, [N]o ");
+	} else {
+		eprint!("{question}, allow? [y/N] ");
+	}
+	let _ = std::io::stderr().flush();
+
+	let answer = lines.recv().await.unwrap_or_default();
+	Ok(match answer.trim() {
+		"y" | "Y" | "yes" => Answer::Once,
+		"r" | "R" if rememberable => Answer::Remember,
+		"a" | "A" | "always" if rememberable => Answer::Always,
+		_ => Answer::No
+	})
+}
+
+fn read_stdin() -> mpsc::UnboundedReceiver<String> {
+	let (tx, rx) = mpsc::unbounded_channel();
+	std::thread::spawn(move || {
+		for line in std::io::stdin().lock().lines() {
+			let Ok(line) = line else { break };
+			if tx.send(line).is_err() {
+				break;
+			}
+		}
+	});
+
+	rx
+}
+
+#[cfg(target_os = "linux")]
+async fn notification(question: &str, rememberable: bool) -> Result<Answer, Error> {
+	use futures::stream::StreamExt;
+	use notifications::NotificationsProxy;
+	use std::collections::HashMap;
+	use zbus::zvariant::Value;
+
+	let dbus_error = |e: zbus::Error| Error::new(ErrorCode::Internal, format!("failed to show notification: {e}"));
+
+	let conn = zbus::Connection::session().await.map_err(dbus_error)?;
+	let proxy = NotificationsProxy::new(&conn).await.map_err(dbus_error)?;
+
+	// listen before showing it, or a quick click could come and go unheard
+	let mut invoked = proxy.receive_action_invoked().await.map_err(dbus_error)?;
+	let mut closed = proxy.receive_notification_closed().await.map_err(dbus_error)?;
+
+	// critical urgency and no expiry keep it on screen until it's answered or we give up on it
+	let hints = HashMap::from([("urgency", Value::U8(2))]);
+	// pairs of action keys and button labels
+	let remember_label = format!("Allow for {} minutes", config::get().consent.remember_minutes);
+	let actions: &[&str] = if rememberable {
+		&["allow", "Allow once", "remember", &remember_label, "always", "Always allow", "deny", "Deny"]
+	} else {
+		&["allow", "Allow", "deny", "Deny"]
+	};
+
+	// most notification servers render a little markup in the body, which the origin and client mustn't get to use
+	let body = format!("{question}, allow?").replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+	let id = proxy.notify("tpm-ws", 0, "dialog-password", "tpm-ws", &body, actions, hints, 0)
+		.await.map_err(dbus_error)?;
+	let _close = notifications::CloseOnDrop(proxy.clone(), id);
+
+	loop {
+		tokio::select! {
+			Some(signal) = invoked.next() => {
+				let args = signal.args().map_err(dbus_error)?;
+				if args.id == id {
+					return Ok(match args.action_key.as_str() {
+						"allow" => Answer::Once,
+						"remember" if rememberable => Answer::Remember,
+						"always" if rememberable => Answer::Always,
+						_ => Answer::No
+					});
+				}
+			},
+			Some(signal) = closed.next() => {
+				if signal.args().map_err(dbus_error)?.id == id {
+					return Ok(Answer::No);
+				}
+			},
+			else => return Ok(Answer::No)
+		}
+	}
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn notification(_question: &str, _rememberable: bool) -> Result<Answer, Error> {
+	Err(Error::new(ErrorCode::Internal, "desktop notifications are only supported on linux"))
+}
+
+#[cfg(target_os = "linux")]
+mod notifications {
+	use std::collections::HashMap;
+	use zbus::zvariant::Value;
+
+	#[zbus::proxy(
+		interface = "org.freedesktop.Notifications",
+		default_service = "org.freedesktop.Notifications",
+		default_path = "/org/freedesktop/Notifications"
+	)]
+	pub trait Notifications {
+		#[allow(clippy::too_many_arguments)] // the spec's, not ours
+		fn notify(&self, app_name: &str, replaces_id: u32, app_icon: &str, summary: &str, body: &str, actions: &[&str], hints: HashMap<&str, Value<'_>>, expire_timeout: i32) -> zbus::Result<u32>;
+
+		fn close_notification(&self, id: u32) -> zbus::Result<()>;
+
+		#[zbus(signal)]
+		fn action_invoked(&self, id: u32, action_key: String) -> zbus::Result<()>;
+
+		#[zbus(signal)]
+		fn notification_closed(&self, id: u32, reason: u32) -> zbus::Result<()>;
+	}
+
+	// an answer that comes too late shouldn't be left on screen looking like it still matters
+	pub struct CloseOnDrop(pub NotificationsProxy<'static>, pub u32);
+
+	impl Drop for CloseOnDrop {
+		fn drop(&mut self) {
+			let (proxy, id) = (self.0.clone(), self.1);
+			tokio::spawn(async move {
+				let _ = proxy.close_notification(id).await;
+			});
+		}
+	}
+}
+
+// what the command gets on stdin, as json
+#[derive(Serialize)]
+struct CommandInput<'a> {
+	origin: &'a str,
+	client: &'a str,
+	grantee: Option<&'a str>,
+	action: &'static str,
+	// hex, like sha256sum prints
+	data_sha256: Option<String>,
+	display: Option<&'a str>,
+	peer: Option<&'a Peer>
+}
+
+// the command gets everything the other methods would show, as json on stdin and, for simple scripts, as environment variables
+// it denies by exiting unsuccessfully or printing "deny", and allows by exiting successfully having printed nothing else but "allow"
+// it's asked every time, anything it wants to remember it can remember itself
+async fn command(command: &[String], prompt: &Prompt) -> Result<Answer, Error> {
+	let (program, args) = command.split_first()
+		.ok_or_else(|| Error::new(ErrorCode::Internal, "no consent command configured"))?;
+
+	let input = serde_json::to_vec(&CommandInput {
+		origin: &prompt.origin,
+		client: &prompt.client,
+		grantee: prompt.grantee.as_deref(),
+		action: prompt.action.name(),
+		data_sha256: prompt.data_sha256.map(|digest| digest.iter().map(|b| format!("{b:02x}")).collect()),
+		display: prompt.display.as_deref(),
+		peer: prompt.peer.as_ref()
+	}).map_err(|e| Error::new(ErrorCode::Internal, format!("failed to encode input for {program}: {e}")))?;
+
+	let mut child = tokio::process::Command::new(program)
+		.args(args)
+		.env("BUNKER_ORIGIN", &prompt.origin)
+		.env("BUNKER_CLIENT", &prompt.client)
+		.env("BUNKER_ACTION", prompt.action.name())
+		.env("BUNKER_DISPLAY", prompt.display.as_deref().unwrap_or_default())
+		.stdin(Stdio::piped())
+		.stdout(Stdio::piped())
+		// a command that outlives its timeout gets killed along with the future
+		.kill_on_drop(true)
+		.spawn()
+		.map_err(|e| Error::new(ErrorCode::Internal, format!("failed to run {program}: {e}")))?;
+
+	// a command that decides without reading its input closes stdin early, which is fine
+	let mut stdin = child.stdin.take().unwrap();
+	if let Err(e) = stdin.write_all(&input).await {
+		log::debug!("{program} didn't take its input: {e}");
+	}
+	drop(stdin);
+
+	let output = child.wait_with_output().await
+		.map_err(|e| Error::new(ErrorCode::Internal, format!("failed to run {program}: {e}")))?;
+
+	Ok(command_answer(program, output.status.success(), &output.stdout))
+}
+
+fn command_answer(program: &str, success: bool, stdout: &[u8]) -> Answer {
+	let said = String::from_utf8_lossy(stdout);
+	match (success, said.trim()) {
+		(true, "" | "allow") => Answer::Once,
+		(true, "deny") => Answer::No,
+		(true, said) => {
+			log::warn!("{program} exited successfully but said {said:?}, assuming no");
+			Answer::No
+		},
+		(false, _) => Answer::No
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn expiry() {
+		assert!(is_live(None, 0));
+		assert!(is_live(None, i64::MAX));
+		assert!(is_live(Some(100), 99));
+		assert!(!is_live(Some(100), 100));
+		assert!(!is_live(Some(100), 101));
+	}
+
+	#[test]
+	fn rememberable() {
+		let prompt = |grantee: Option<&str>, action| Prompt {
+			client: "app".to_string(),
+			grantee: grantee.map(str::to_string),
+			origin: "example.com".to_string(),
+			action,
+			display: None,
+			data_sha256: None,
+			required: false,
+			peer: None
+		};
+
+		assert!(prompt(Some("paired app 1"), Action::Authenticate).rememberable());
+		assert!(!prompt(Some("paired app 1"), Action::Register).rememberable());
+		assert!(!prompt(Some("paired app 1"), Action::Delete).rememberable());
+		// pages and unpaired apps could be anyone, so they're asked every time
+		assert!(!prompt(None, Action::Authenticate).rememberable());
+
+		let mut displayed = prompt(Some("paired app 1"), Action::Authenticate);
+		displayed.display = Some("pay 10 euros".to_string());
+		assert!(!displayed.rememberable());
+
+		let mut required = prompt(Some("paired app 1"), Action::Authenticate);
+		required.required = true;
+		assert!(!required.rememberable());
+	}
+	#[test]
+	fn command_answers() {
+		let cases: [(bool, &[u8], Answer); 8] = [
+			(true, b"", Answer::Once),
+			(true, b"allow\n", Answer::Once),
+			(true, b"  allow  ", Answer::Once),
+			(true, b"deny\n", Answer::No),
+			// anything unexpected is a no, so a confused script can't allow by accident
+			(true, b"yes", Answer::No),
+			(true, b"allow\nallow", Answer::No),
+			(false, b"", Answer::No),
+			(false, b"allow", Answer::No)
+		];
+
+		for (success, stdout, answer) in cases {
+			assert_eq!(command_answer("approve", success, stdout), answer, "{success} {stdout:?}");
+		}
+	}
+}
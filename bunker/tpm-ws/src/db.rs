@@ -10,19 +10,115 @@ tpm-ws is distributed in the hope that it will be useful, but WITHOUT ANY WARRAN
 You should have received a copy of the GNU General Public License along with tpm-ws. If not, see <https://www.gnu.org/licenses/>.
 */
 
-use diesel::{sql_query, Connection, RunQueryDsl, sqlite::SqliteConnection};
+use diesel::{sql_query, Connection, ExpressionMethods, QueryDsl, RunQueryDsl, sqlite::SqliteConnection};
 use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+use crate::error::Error;
+use crate::secrets;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-pub fn get_conn() -> SqliteConnection {
-	let mut conn = SqliteConnection::establish("db.sqlite").unwrap();
-	sql_query("PRAGMA foreign_keys = ON;").execute(&mut conn).unwrap();
-	sql_query("PRAGMA busy_timeout = 500;").execute(&mut conn).unwrap();
+pub fn get_conn() -> Result<SqliteConnection, Error> {
+	let mut conn = SqliteConnection::establish("db.sqlite")?;
+	sql_query("PRAGMA foreign_keys = ON;").execute(&mut conn)?;
+	sql_query("PRAGMA busy_timeout = 500;").execute(&mut conn)?;
 
-	conn
+	Ok(conn)
+}
+
+// timestamps are stored as seconds since the unix epoch
+pub fn now() -> i64 {
+	SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |t| t.as_secs() as i64)
 }
 
 pub fn run_migrations() {
-	let mut conn = get_conn();
+	let mut conn = get_conn().unwrap();
 	const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
 	conn.run_pending_migrations(MIGRATIONS).unwrap();
 }
+
+// keys stored before origins were canonicalized can be under a name no request will ever canonicalize to
+// this moves each one to its canonical name, along with its secret in the keyring, which is named after the origin too
+pub async fn canonicalize_origins() {
+	use crate::schema::software_keys;
+
+	let mut conn = get_conn().unwrap();
+
+	let origins: Vec<String> = software_keys::table.select(software_keys::origin).load(&mut conn).unwrap();
+	for (origin, canonical) in renames(origins) {
+		match rename_software_key(&mut conn, &origin, &canonical).await {
+			Ok(()) => log::info!("moved the key for {origin:?} to {canonical:?}"),
+			Err(e) => log::error!("failed to move the key for {origin:?} to {canonical:?}, so it can't be used until that's sorted out: {e}")
+		}
+	}
+
+	#[cfg(all(feature = "tpm", target_os = "linux"))]
+	{
+		use crate::schema::tpm_keys;
+
+		let origins: Vec<String> = tpm_keys::table.select(tpm_keys::origin).load(&mut conn).unwrap();
+		for (origin, canonical) in renames(origins) {
+			match rename_tpm_key(&mut conn, &origin, &canonical).await {
+				Ok(()) => log::info!("moved the key for {origin:?} to {canonical:?}"),
+				Err(e) => log::error!("failed to move the key for {origin:?} to {canonical:?}, so it can't be used until that's sorted out: {e}")
+			}
+		}
+	}
+}
+
+fn renames(origins: Vec<String>) -> Vec<(String, String)> {
+	origins.into_iter().filter_map(|origin| match crate::origin::canonicalize(&origin) {
+		Ok(canonical) if canonical == origin => None,
+		Ok(canonical) => Some((origin, canonical)),
+		Err(e) => {
+			log::error!("the key stored for {origin:?} can't be used, since it isn't a valid origin: {e}");
+			None
+		}
+	}).collect()
+}
+
+// the secret is copied before the row moves and deleted after, so a failure at any point leaves a key that works under one name or the other
+async fn rename_software_key(conn: &mut SqliteConnection, from: &str, to: &str) -> Result<(), Error> {
+	use crate::schema::software_keys::dsl;
+
+	let taken: i64 = dsl::software_keys.filter(dsl::origin.eq(to)).count().get_result(conn)?;
+	if taken > 0 {
+		return Err(Error::key_exists(to));
+	}
+
+	let copied = secrets::copy_aes_key(from, to).await?;
+	if let Err(e) = diesel::update(dsl::software_keys.filter(dsl::origin.eq(from))).set(dsl::origin.eq(to)).execute(conn) {
+		if copied {
+			let _ = secrets::delete_aes_key(to).await;
+		}
+		return Err(e.into());
+	}
+
+	if copied {
+		secrets::delete_aes_key(from).await?;
+	}
+
+	Ok(())
+}
+
+#[cfg(all(feature = "tpm", target_os = "linux"))]
+async fn rename_tpm_key(conn: &mut SqliteConnection, from: &str, to: &str) -> Result<(), Error> {
+	use crate::schema::tpm_keys::dsl;
+
+	let taken: i64 = dsl::tpm_keys.filter(dsl::origin.eq(to)).count().get_result(conn)?;
+	if taken > 0 {
+		return Err(Error::key_exists(to));
+	}
+
+	let copied = secrets::copy_password(from, to).await?;
+	if let Err(e) = diesel::update(dsl::tpm_keys.filter(dsl::origin.eq(from))).set(dsl::origin.eq(to)).execute(conn) {
+		if copied {
+			let _ = secrets::delete_password(to).await;
+		}
+		return Err(e.into());
+	}
+
+	if copied {
+		secrets::delete_password(from).await?;
+	}
+
+	Ok(())
+}
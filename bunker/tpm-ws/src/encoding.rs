@@ -0,0 +1,427 @@
+/*
+Copyright James Connolly 2024
+
+This file is part of tpm-ws.
+
+tpm-ws is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+
+tpm-ws is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with tpm-ws. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+// responses always go out in whatever the request came in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+	// binary frames, structs as positional arrays
+	Msgpack,
+	// text frames, structs as objects with named fields
+	Json,
+	// binary frames, structs as maps with named fields, only if the client negotiated it during the handshake
+	Cbor
+}
+
+impl Encoding {
+	pub const ALL: [Encoding; 3] = [Encoding::Msgpack, Encoding::Json, Encoding::Cbor];
+
+	pub fn name(self) -> &'static str {
+		match self {
+			Encoding::Msgpack => "msgpack",
+			Encoding::Json => "json",
+			Encoding::Cbor => "cbor"
+		}
+	}
+
+	// `bunker.v<protocol version>.<encoding>`
+	pub fn subprotocol(self, version: u32) -> String {
+		format!("bunker.v{version}.{}", self.name())
+	}
+
+	// `negotiated` decides what binary frames are, text frames are always json
+	// `None` for frames that don't carry a request at all, like pings
+	pub fn of(msg: &Message, negotiated: Encoding) -> Option<Encoding> {
+		match msg {
+			Message::Binary(_) if negotiated == Encoding::Cbor => Some(Encoding::Cbor),
+			Message::Binary(_) => Some(Encoding::Msgpack),
+			Message::Text(_) => Some(Encoding::Json),
+			_ => None
+		}
+	}
+
+	// the inverse of `subprotocol`, anything that isn't named like one is none of ours
+	pub fn parse_subprotocol(protocol: &str) -> Option<(u32, Encoding)> {
+		let (version, encoding) = protocol.strip_prefix("bunker.v")?.split_once('.')?;
+		let encoding = Encoding::ALL.into_iter().find(|e| e.name() == encoding)?;
+
+		// no leading zeros or signs, so every version has exactly one name
+		if version.starts_with('0') || !version.bytes().all(|b| b.is_ascii_digit()) {
+			return None;
+		}
+
+		Some((version.parse().ok()?, encoding))
+	}
+
+	pub fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, String> {
+		match self {
+			Encoding::Msgpack => rmp_serde::from_slice(bytes).map_err(|e| e.to_string()),
+			Encoding::Json => serde_json::from_slice(bytes).map_err(|e| e.to_string()),
+			Encoding::Cbor => ciborium::de::from_reader(bytes).map_err(|e| e.to_string())
+		}
+	}
+
+	pub fn encode<T: Serialize>(self, value: &T) -> Result<Message, String> {
+		match self {
+			Encoding::Msgpack => rmp_serde::to_vec(&byte_arrays::ByteArrays(value)).map(Message::Binary).map_err(|e| e.to_string()),
+			Encoding::Json => serde_json::to_string(value).map(Message::Text).map_err(|e| e.to_string()),
+			Encoding::Cbor => {
+				let mut encoded = Vec::new();
+				ciborium::ser::into_writer(value, &mut encoded).map_err(|e| e.to_string())?;
+				Ok(Message::Binary(encoded))
+			}
+		}
+	}
+}
+
+// byte strings as base64url in json, and as real byte strings in cbor and msgpack, though see `byte_arrays`
+// an array of numbers is accepted from either binary encoding
+pub mod bytes {
+	use base64::Engine;
+	use base64::alphabet::URL_SAFE;
+	use base64::engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig};
+	use serde::{Deserialize, Deserializer, Serialize, Serializer};
+	use serde::de::{Error, SeqAccess, Visitor};
+	use std::fmt;
+
+	// javascript's own base64url helpers disagree on padding, so take either
+	const BASE64URL: GeneralPurpose = GeneralPurpose::new(&URL_SAFE, GeneralPurposeConfig::new()
+		.with_encode_padding(false)
+		.with_decode_padding_mode(DecodePaddingMode::Indifferent));
+
+	pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+		if serializer.is_human_readable() {
+			BASE64URL.encode(bytes).serialize(serializer)
+		} else {
+			serializer.serialize_bytes(bytes)
+		}
+	}
+
+	pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+		if deserializer.is_human_readable() {
+			let encoded = String::deserialize(deserializer)?;
+			BASE64URL.decode(encoded).map_err(|e| D::Error::custom(format!("invalid base64url: {e}")))
+		} else {
+			deserializer.deserialize_byte_buf(BytesVisitor)
+		}
+	}
+
+	struct BytesVisitor;
+
+	impl<'de> Visitor<'de> for BytesVisitor {
+		type Value = Vec<u8>;
+
+		fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+			write!(f, "a byte string or an array of bytes")
+		}
+
+		fn visit_bytes<E: Error>(self, bytes: &[u8]) -> Result<Vec<u8>, E> {
+			Ok(bytes.to_vec())
+		}
+
+		fn visit_byte_buf<E: Error>(self, bytes: Vec<u8>) -> Result<Vec<u8>, E> {
+			Ok(bytes)
+		}
+
+		fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+			let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(4096));
+			while let Some(byte) = seq.next_element()? {
+				bytes.push(byte);
+			}
+			Ok(bytes)
+		}
+	}
+}
+
+// msgpack clients have always been sent byte strings as arrays of numbers, so this passes everything through to rmp_serde as is except byte strings
+mod byte_arrays {
+	use serde::ser::{self, Serialize, Serializer};
+
+	pub struct ByteArrays<'a, T: ?Sized>(pub &'a T);
+
+	impl<T: ?Sized + Serialize> Serialize for ByteArrays<'_, T> {
+		fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+			self.0.serialize(Wrapper(serializer))
+		}
+	}
+
+	// wraps rmp_serde's serializer and each of its compound serializers
+	struct Wrapper<S>(S);
+
+	impl<S: Serializer> Serializer for Wrapper<S> {
+		type Ok = S::Ok;
+		type Error = S::Error;
+		type SerializeSeq = Wrapper<S::SerializeSeq>;
+		type SerializeTuple = Wrapper<S::SerializeTuple>;
+		type SerializeTupleStruct = Wrapper<S::SerializeTupleStruct>;
+		type SerializeTupleVariant = Wrapper<S::SerializeTupleVariant>;
+		type SerializeMap = Wrapper<S::SerializeMap>;
+		type SerializeStruct = Wrapper<S::SerializeStruct>;
+		type SerializeStructVariant = Wrapper<S::SerializeStructVariant>;
+
+		fn serialize_bytes(self, v: &[u8]) -> Result<S::Ok, S::Error> {
+			self.0.collect_seq(v)
+		}
+
+		fn serialize_bool(self, v: bool) -> Result<S::Ok, S::Error> {
+			self.0.serialize_bool(v)
+		}
+
+		fn serialize_i8(self, v: i8) -> Result<S::Ok, S::Error> {
+			self.0.serialize_i8(v)
+		}
+
+		fn serialize_i16(self, v: i16) -> Result<S::Ok, S::Error> {
+			self.0.serialize_i16(v)
+		}
+
+		fn serialize_i32(self, v: i32) -> Result<S::Ok, S::Error> {
+			self.0.serialize_i32(v)
+		}
+
+		fn serialize_i64(self, v: i64) -> Result<S::Ok, S::Error> {
+			self.0.serialize_i64(v)
+		}
+
+		fn serialize_u8(self, v: u8) -> Result<S::Ok, S::Error> {
+			self.0.serialize_u8(v)
+		}
+
+		fn serialize_u16(self, v: u16) -> Result<S::Ok, S::Error> {
+			self.0.serialize_u16(v)
+		}
+
+		fn serialize_u32(self, v: u32) -> Result<S::Ok, S::Error> {
+			self.0.serialize_u32(v)
+		}
+
+		fn serialize_u64(self, v: u64) -> Result<S::Ok, S::Error> {
+			self.0.serialize_u64(v)
+		}
+
+		fn serialize_f32(self, v: f32) -> Result<S::Ok, S::Error> {
+			self.0.serialize_f32(v)
+		}
+
+		fn serialize_f64(self, v: f64) -> Result<S::Ok, S::Error> {
+			self.0.serialize_f64(v)
+		}
+
+		fn serialize_char(self, v: char) -> Result<S::Ok, S::Error> {
+			self.0.serialize_char(v)
+		}
+
+		fn serialize_str(self, v: &str) -> Result<S::Ok, S::Error> {
+			self.0.serialize_str(v)
+		}
+
+		fn serialize_none(self) -> Result<S::Ok, S::Error> {
+			self.0.serialize_none()
+		}
+
+		fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<S::Ok, S::Error> {
+			self.0.serialize_some(&ByteArrays(value))
+		}
+
+		fn serialize_unit(self) -> Result<S::Ok, S::Error> {
+			self.0.serialize_unit()
+		}
+
+		fn serialize_unit_struct(self, name: &'static str) -> Result<S::Ok, S::Error> {
+			self.0.serialize_unit_struct(name)
+		}
+
+		fn serialize_unit_variant(self, name: &'static str, index: u32, variant: &'static str) -> Result<S::Ok, S::Error> {
+			self.0.serialize_unit_variant(name, index, variant)
+		}
+
+		fn serialize_newtype_struct<T: ?Sized + Serialize>(self, name: &'static str, value: &T) -> Result<S::Ok, S::Error> {
+			self.0.serialize_newtype_struct(name, &ByteArrays(value))
+		}
+
+		fn serialize_newtype_variant<T: ?Sized + Serialize>(self, name: &'static str, index: u32, variant: &'static str, value: &T) -> Result<S::Ok, S::Error> {
+			self.0.serialize_newtype_variant(name, index, variant, &ByteArrays(value))
+		}
+
+		fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq, S::Error> {
+			self.0.serialize_seq(len).map(Wrapper)
+		}
+
+		fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple, S::Error> {
+			self.0.serialize_tuple(len).map(Wrapper)
+		}
+
+		fn serialize_tuple_struct(self, name: &'static str, len: usize) -> Result<Self::SerializeTupleStruct, S::Error> {
+			self.0.serialize_tuple_struct(name, len).map(Wrapper)
+		}
+
+		fn serialize_tuple_variant(self, name: &'static str, index: u32, variant: &'static str, len: usize) -> Result<Self::SerializeTupleVariant, S::Error> {
+			self.0.serialize_tuple_variant(name, index, variant, len).map(Wrapper)
+		}
+
+		fn serialize_map(self, len: Option<usize>) -> Result<Self::SerializeMap, S::Error> {
+			self.0.serialize_map(len).map(Wrapper)
+		}
+
+		fn serialize_struct(self, name: &'static str, len: usize) -> Result<Self::SerializeStruct, S::Error> {
+			self.0.serialize_struct(name, len).map(Wrapper)
+		}
+
+		fn serialize_struct_variant(self, name: &'static str, index: u32, variant: &'static str, len: usize) -> Result<Self::SerializeStructVariant, S::Error> {
+			self.0.serialize_struct_variant(name, index, variant, len).map(Wrapper)
+		}
+
+		fn is_human_readable(&self) -> bool {
+			self.0.is_human_readable()
+		}
+	}
+
+	impl<S: ser::SerializeSeq> ser::SerializeSeq for Wrapper<S> {
+		type Ok = S::Ok;
+		type Error = S::Error;
+
+		fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), S::Error> {
+			self.0.serialize_element(&ByteArrays(value))
+		}
+
+		fn end(self) -> Result<S::Ok, S::Error> {
+			self.0.end()
+		}
+	}
+
+	impl<S: ser::SerializeTuple> ser::SerializeTuple for Wrapper<S> {
+		type Ok = S::Ok;
+		type Error = S::Error;
+
+		fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), S::Error> {
+			self.0.serialize_element(&ByteArrays(value))
+		}
+
+		fn end(self) -> Result<S::Ok, S::Error> {
+			self.0.end()
+		}
+	}
+
+	impl<S: ser::SerializeTupleStruct> ser::SerializeTupleStruct for Wrapper<S> {
+		type Ok = S::Ok;
+		type Error = S::Error;
+
+		fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), S::Error> {
+			self.0.serialize_field(&ByteArrays(value))
+		}
+
+		fn end(self) -> Result<S::Ok, S::Error> {
+			self.0.end()
+		}
+	}
+
+	impl<S: ser::SerializeTupleVariant> ser::SerializeTupleVariant for Wrapper<S> {
+		type Ok = S::Ok;
+		type Error = S::Error;
+
+		fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), S::Error> {
+			self.0.serialize_field(&ByteArrays(value))
+		}
+
+		fn end(self) -> Result<S::Ok, S::Error> {
+			self.0.end()
+		}
+	}
+
+	impl<S: ser::SerializeMap> ser::SerializeMap for Wrapper<S> {
+		type Ok = S::Ok;
+		type Error = S::Error;
+
+		fn serialize_key<T: ?Sized + Serialize>(&mut self, key: &T) -> Result<(), S::Error> {
+			self.0.serialize_key(&ByteArrays(key))
+		}
+
+		fn serialize_value<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), S::Error> {
+			self.0.serialize_value(&ByteArrays(value))
+		}
+
+		fn end(self) -> Result<S::Ok, S::Error> {
+			self.0.end()
+		}
+	}
+
+	impl<S: ser::SerializeStruct> ser::SerializeStruct for Wrapper<S> {
+		type Ok = S::Ok;
+		type Error = S::Error;
+
+		fn serialize_field<T: ?Sized + Serialize>(&mut self, key: &'static str, value: &T) -> Result<(), S::Error> {
+			self.0.serialize_field(key, &ByteArrays(value))
+		}
+
+		fn skip_field(&mut self, key: &'static str) -> Result<(), S::Error> {
+			self.0.skip_field(key)
+		}
+
+		fn end(self) -> Result<S::Ok, S::Error> {
+			self.0.end()
+		}
+	}
+
+	impl<S: ser::SerializeStructVariant> ser::SerializeStructVariant for Wrapper<S> {
+		type Ok = S::Ok;
+		type Error = S::Error;
+
+		fn serialize_field<T: ?Sized + Serialize>(&mut self, key: &'static str, value: &T) -> Result<(), S::Error> {
+			self.0.serialize_field(key, &ByteArrays(value))
+		}
+
+		fn skip_field(&mut self, key: &'static str) -> Result<(), S::Error> {
+			self.0.skip_field(key)
+		}
+
+		fn end(self) -> Result<S::Ok, S::Error> {
+			self.0.end()
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn subprotocols() {
+		let cases = [
+			("bunker.v1.msgpack", Some((1, Encoding::Msgpack))),
+			("bunker.v2.json", Some((2, Encoding::Json))),
+			("bunker.v10.cbor", Some((10, Encoding::Cbor))),
+			("bunker.v2.xml", None),
+			("bunker.v2", None),
+			("bunker.v.json", None),
+			("bunker.v02.json", None),
+			("bunker.v0.json", None),
+			("bunker.v+2.json", None),
+			("bunker.v-2.json", None),
+			("bunker.v99999999999.json", None),
+			("bunker.V2.json", None),
+			("bunker.v2.JSON", None),
+			("other.v2.json", None),
+			("", None)
+		];
+
+		for (protocol, parsed) in cases {
+			assert_eq!(Encoding::parse_subprotocol(protocol), parsed, "{protocol}");
+		}
+
+		for encoding in Encoding::ALL {
+			assert_eq!(Encoding::parse_subprotocol(&encoding.subprotocol(2)), Some((2, encoding)));
+		}
+	}
+}
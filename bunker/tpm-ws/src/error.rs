@@ -0,0 +1,84 @@
+/*
+Copyright James Connolly 2024
+
+This file is part of tpm-ws.
+
+tpm-ws is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+
+tpm-ws is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with tpm-ws. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use serde::Serialize;
+use std::fmt;
+
+// these are part of the wire protocol, so never rename or reuse a variant
+#[allow(dead_code)] // not every build has a backend that reports every code
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+	MalformedRequest,
+	InvalidOrigin,
+	Forbidden,
+	BackendUnavailable,
+	KeyringLocked,
+	KeyNotFound,
+	KeyExists,
+	ConsentDenied,
+	TpmLockout,
+	Pkcs11LoginFailed,
+	Internal,
+	Unauthenticated,
+	PairingFailed,
+	ConsentTimeout,
+	RateLimited
+}
+
+#[derive(Serialize, Debug)]
+pub struct Error {
+	pub code: ErrorCode,
+	pub message: String
+}
+
+impl Error {
+	pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+		Error {
+			code,
+			message: message.into()
+		}
+	}
+
+	pub fn key_not_found(origin: &str) -> Self {
+		Error::new(ErrorCode::KeyNotFound, format!("no key exists for {origin}"))
+	}
+
+	pub fn key_exists(origin: &str) -> Self {
+		Error::new(ErrorCode::KeyExists, format!("a key already exists for {origin}"))
+	}
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{:?}: {}", self.code, self.message)
+	}
+}
+
+impl std::error::Error for Error {}
+
+impl From<tokio::task::JoinError> for Error {
+	fn from(e: tokio::task::JoinError) -> Self {
+		Error::new(ErrorCode::Internal, format!("worker task failed: {e}"))
+	}
+}
+
+impl From<diesel::ConnectionError> for Error {
+	fn from(e: diesel::ConnectionError) -> Self {
+		Error::new(ErrorCode::BackendUnavailable, format!("failed to open database: {e}"))
+	}
+}
+
+impl From<diesel::result::Error> for Error {
+	fn from(e: diesel::result::Error) -> Self {
+		Error::new(ErrorCode::Internal, format!("database error: {e}"))
+	}
+}
@@ -10,16 +10,38 @@ tpm-ws is distributed in the hope that it will be useful, but WITHOUT ANY WARRAN
 You should have received a copy of the GNU General Public License along with tpm-ws. If not, see <https://www.gnu.org/licenses/>.
 */
 
-use tokio::net::TcpListener;
 use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, Semaphore};
 use futures::stream::StreamExt;
 use futures::sink::SinkExt;
-use tokio_tungstenite::WebSocketStream;
 use tokio_tungstenite::tungstenite::protocol::Message;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::StatusCode;
 use serde::{Serialize, Deserialize};
+use serde::de::IgnoredAny;
+use sha2::{Sha256, Digest};
 use std::fmt::Debug;
+use std::future::Future;
+use std::sync::Arc;
+
+mod error;
+use error::{Error, ErrorCode};
+
+mod config;
+
+mod encoding;
+use encoding::Encoding;
+
+mod origin;
+mod policy;
+mod ratelimit;
+mod unix;
+mod tls;
 
 mod secrets;
+mod consent;
+mod pairing;
 
 mod db;
 mod schema;
@@ -41,12 +63,52 @@ use pkcs11::Pkcs11Backend;
 mod software;
 use software::SoftwareBackend;
 
+const PORT: u16 = 8000;
+
+// names a browser can reach us by without going through dns, which a rebinding page could point at us under its own name
+const LOOPBACK_HOSTS: &[&str] = &["localhost", "127.0.0.1", "[::1]"];
+
+// bump this whenever a change to `Msg` or `Resp` would break existing clients
+const PROTOCOL_VERSION: u32 = 2;
+
+// every backend only knows how to make P-256 keys for now
+const SUPPORTED_CURVES: &[&str] = &["P-256"];
+
+// every version a client can still ask for with a subprotocol, so old clients keep working after breaking changes
+const SUPPORTED_PROTOCOL_VERSIONS: &[u32] = &[1, PROTOCOL_VERSION];
+
+// how many requests with an id one connection can have running at once, reading stops until one finishes
+const MAX_IN_FLIGHT: usize = 16;
+
+// enough for a transaction summary, not so much that it won't fit in a notification
+const MAX_DISPLAY_LEN: usize = 512;
+
+// the most the tpm can hash in one go, which is the least of any backend, so a `display` gets the same answer everywhere
+const MAX_BOUND_LEN: usize = 1024;
+
+// starts what gets signed when a request has a `display`, see `bind_display`
+const DISPLAY_PREFIX: &[u8] = b"bunker display v1\0";
+
 trait Backend: Default + Debug {
 	fn is_supported() -> bool;
 
-	fn sign_msg<S>(&self, ws: &mut WebSocketStream<S>, sign_msg: SignMsg) -> impl std::future::Future<Output = ()>
-	where
-		S: AsyncRead + AsyncWrite + Unpin;
+	fn sign(&self, sign_msg: SignMsg, mode: SignMode) -> impl Future<Output = Result<SignResp, Error>> + Send;
+
+	// must never create a key
+	fn get_public_key(&self, origin: String) -> impl Future<Output = Result<EcPoint, Error>> + Send;
+
+	fn list_keys(&self) -> impl Future<Output = Result<Vec<KeyInfo>, Error>> + Send;
+
+	// removes everything we stored for the origin, or nothing at all
+	fn delete_key(&self, origin: String) -> impl Future<Output = Result<(), Error>> + Send;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SignMode {
+	// creates the key, failing if the origin already has one
+	Register,
+	// uses the existing key, failing if the origin doesn't have one
+	Authenticate
 }
 
 #[derive(Debug)]
@@ -56,19 +118,200 @@ enum SelectedBackend {
 	Software(SoftwareBackend)
 }
 
+impl SelectedBackend {
+	fn backend_type(&self) -> BackendType {
+		match self {
+			SelectedBackend::Tpm(_) => BackendType::Tpm,
+			SelectedBackend::Pkcs11(_) => BackendType::Pkcs11,
+			SelectedBackend::Software(_) => BackendType::Software
+		}
+	}
+
+	async fn sign(&self, sign_msg: SignMsg, mode: SignMode) -> Result<SignResp, Error> {
+		match self {
+			SelectedBackend::Tpm(tpm) => tpm.sign(sign_msg, mode).await,
+			SelectedBackend::Pkcs11(pkcs11) => pkcs11.sign(sign_msg, mode).await,
+			SelectedBackend::Software(software) => software.sign(sign_msg, mode).await
+		}
+	}
+
+	async fn get_public_key(&self, origin: String) -> Result<EcPoint, Error> {
+		match self {
+			SelectedBackend::Tpm(tpm) => tpm.get_public_key(origin).await,
+			SelectedBackend::Pkcs11(pkcs11) => pkcs11.get_public_key(origin).await,
+			SelectedBackend::Software(software) => software.get_public_key(origin).await
+		}
+	}
+
+	async fn list_keys(&self) -> Result<Vec<KeyInfo>, Error> {
+		match self {
+			SelectedBackend::Tpm(tpm) => tpm.list_keys().await,
+			SelectedBackend::Pkcs11(pkcs11) => pkcs11.list_keys().await,
+			SelectedBackend::Software(software) => software.list_keys().await
+		}
+	}
+
+	async fn delete_key(&self, origin: String) -> Result<(), Error> {
+		match self {
+			SelectedBackend::Tpm(tpm) => tpm.delete_key(origin).await,
+			SelectedBackend::Pkcs11(pkcs11) => pkcs11.delete_key(origin).await,
+			SelectedBackend::Software(software) => software.delete_key(origin).await
+		}
+	}
+}
+
+// what we learned about the other end during the handshake, and since
+#[derive(Debug)]
+struct Client {
+	protocol_version: u32,
+	// browsers always send this, and pages can't leave it out or change it
+	browser_origin: Option<String>,
+	pairing: std::sync::Mutex<pairing::Session>,
+	// for signing requests, see ratelimit.rs
+	rate_limit: std::sync::Mutex<ratelimit::Bucket>,
+	// only for connections over the unix socket
+	peer: Option<unix::Peer>
+}
+
+impl Default for Client {
+	// clients that don't ask for a subprotocol predate them, so they speak version 1
+	fn default() -> Self {
+		Client {
+			protocol_version: 1,
+			browser_origin: None,
+			pairing: Default::default(),
+			rate_limit: Default::default(),
+			peer: None
+		}
+	}
+}
+
+impl Client {
+	// web pages only get the key for their own origin, anything else has to be trusted, and either way the policy has to allow it
+	// hands back the canonical form, which is what keys are stored under
+	fn check_origin(&self, origin: &str) -> Result<String, Error> {
+		let origin = self.check_client_origin(origin)?;
+		policy::check(&origin, &self.who()).map(|()| origin)
+	}
+
+	fn check_client_origin(&self, origin: &str) -> Result<String, Error> {
+		let origin = origin::canonicalize(origin)?;
+
+		match &self.browser_origin {
+			// sandboxed pages and the like send `null`, which is no origin at all rather than a host called null
+			Some(browser_origin) if browser_origin != "null" && origin::canonicalize(browser_origin).is_ok_and(|browser_origin| browser_origin == origin) => {
+				self.check_paired().map(|()| origin)
+			},
+			Some(browser_origin) => {
+				log::error!("refusing to let {browser_origin} use the key for {origin}");
+				Err(Error::new(ErrorCode::Forbidden, format!("{browser_origin} may not use the key for {origin}")))
+			},
+			None => self.check_trusted().map(|()| origin)
+		}
+	}
+
+	// for local apps, which can claim to be anyone, so they have to have paired unless the config trusts all of them
+	fn check_trusted(&self) -> Result<(), Error> {
+		if self.browser_origin.is_some() {
+			log::error!("refusing to treat {self:?} as a local app");
+			return Err(Error::new(ErrorCode::Forbidden, "web pages may only use the key for their own origin"));
+		}
+
+		let config = config::get();
+		// the kernel vouches for apps on the unix socket, so trusting them holds even when everyone else has to pair
+		let trusted = (self.peer.is_some() && config.unix_socket.trusted) || (config.allow_non_browser && !config.require_pairing);
+		if self.paired().is_some() || trusted {
+			return Ok(());
+		}
+
+		log::error!("refusing unpaired client {self:?}");
+		Err(Error::new(ErrorCode::Unauthenticated, "local apps have to pair first, unless allow_non_browser is set in config.toml"))
+	}
+
+	fn check_paired(&self) -> Result<(), Error> {
+		if !config::get().require_pairing || self.paired().is_some() {
+			return Ok(());
+		}
+
+		log::error!("refusing unpaired client {self:?}");
+		Err(Error::new(ErrorCode::Unauthenticated, "pair with tpm-ws first"))
+	}
+
+	// the row in `clients` and the name it paired under
+	fn paired(&self) -> Option<(i32, String)> {
+		self.pairing.lock().unwrap().paired().map(|(id, name)| (id, name.to_string()))
+	}
+
+	// what policy rules can tell about who this is
+	fn who(&self) -> policy::Who {
+		let kind = match (&self.browser_origin, self.paired()) {
+			(Some(_), _) => policy::ClientKind::Page,
+			(None, Some((_, name))) => policy::ClientKind::Paired(name),
+			(None, None) => policy::ClientKind::Local
+		};
+
+		policy::Who { kind, peer: self.peer.clone() }
+	}
+
+	// who remembered consent belongs to, for clients that can prove it's them again on another connection
+	fn grantee(&self) -> Option<String> {
+		match (self.paired(), &self.peer) {
+			(Some((id, _)), _) => Some(format!("paired app {id}")),
+			// the kernel vouches for the uid on the other end of the unix socket
+			(None, Some(peer)) => Some(format!("unix uid {}", peer.uid)),
+			(None, None) => None
+		}
+	}
+
+	// what pairing limits are kept per, which has to be something the client can't change by reconnecting
+	fn requester(&self) -> String {
+		match (&self.browser_origin, &self.peer) {
+			(Some(origin), _) => format!("page {origin}"),
+			(None, Some(peer)) => format!("unix uid {}", peer.uid),
+			(None, None) => "local app".to_string()
+		}
+	}
+}
+
+impl std::fmt::Display for Client {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match (&self.browser_origin, self.paired()) {
+			(Some(origin), _) => write!(f, "{origin}"),
+			// the name is the client's to choose, so escape it
+			(None, Some((_, name))) => match &self.peer {
+				Some(peer) => write!(f, "{peer} paired as {name:?}"),
+				None => write!(f, "a local app paired as {name:?}")
+			},
+			(None, None) => match &self.peer {
+				Some(peer) => write!(f, "{peer}"),
+				None => write!(f, "a local app")
+			}
+		}
+	}
+}
+
 #[tokio::main]
 async fn main() {
 	pretty_env_logger::init();
 	log::info!("Copyright James Connolly 2024");
+	config::load();
+	policy::load();
 	db::run_migrations();
+	db::canonicalize_origins().await;
+
+	// prints the ca that signs the wss:// certificate, so it can be trusted, and does nothing else
+	if std::env::args().nth(1).as_deref() == Some("--export-ca") {
+		print!("{}", tls::export_ca().await);
+		return;
+	}
 
 	let selected_backend = {
 		if TpmBackend::is_supported() {
-			SelectedBackend::Tpm(TpmBackend)
+			SelectedBackend::Tpm(TpmBackend::default())
 		} else if Pkcs11Backend::is_supported() {
-			SelectedBackend::Pkcs11(Pkcs11Backend)
+			SelectedBackend::Pkcs11(Pkcs11Backend::default())
 		} else if SoftwareBackend::is_supported() {
-			SelectedBackend::Software(SoftwareBackend)
+			SelectedBackend::Software(SoftwareBackend::default())
 		} else {
 			panic!("no backends supported")
 		}
@@ -76,64 +319,696 @@ async fn main() {
 
 	log::debug!("selected {selected_backend:?}");
 
-	let listener = TcpListener::bind("127.0.0.1:8000").await.unwrap();
-
-	while let Ok((stream, _)) = listener.accept().await {
-		let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
-		log::debug!("accepted connection");
-
-		while let Some(Ok(msg)) = ws.next().await {
-			if let Message::Binary(bytes) = msg {
-				let msg: Msg = rmp_serde::from_slice(&bytes).unwrap();
-
-				match msg {
-					Msg::Sign(sign_msg) => {
-						if sign_msg.origin.chars().any(|c| !c.is_ascii_alphanumeric() && c != '.') {
-							log::error!("invalid origin");
-							let msg = rmp_serde::to_vec(&Resp::Error(String::from("sign origin must be ascii alphanumeric"))).unwrap();
-							ws.send(Message::Binary(msg)).await.unwrap();
-							continue;
-						}
-
-						match &selected_backend {
-							SelectedBackend::Pkcs11(pkcs11) => pkcs11.sign_msg(&mut ws, sign_msg).await,
-							SelectedBackend::Tpm(tpm) => tpm.sign_msg(&mut ws, sign_msg).await,
-							SelectedBackend::Software(software) => software.sign_msg(&mut ws, sign_msg).await
-						}
-					}
+	let listener = TcpListener::bind(("127.0.0.1", PORT)).await.unwrap();
+
+	let selected_backend = Arc::new(selected_backend);
+
+	#[cfg(unix)]
+	if let Some(socket) = unix::bind() {
+		tokio::spawn(unix::serve(socket, Arc::clone(&selected_backend)));
+	}
+
+	if let Some(tls_listener) = tls::bind().await {
+		tokio::spawn(tls::serve(tls_listener, Arc::clone(&selected_backend)));
+	}
+
+	while let Ok((stream, addr)) = listener.accept().await {
+		log::debug!("accepted connection from {addr}");
+		tokio::spawn(handle_connection(stream, Arc::clone(&selected_backend), None, Some(PORT)));
+	}
+}
+
+// serves tcp, tls and the unix socket alike, `peer` is what the kernel told us about the other end of the latter
+// `port` is the one the `Host` header has to name, `None` for the unix socket, which no page can reach by any name
+async fn handle_connection<S>(stream: S, selected_backend: Arc<SelectedBackend>, peer: Option<unix::Peer>, port: Option<u16>)
+where
+	S: AsyncRead + AsyncWrite + Unpin + Send + 'static
+{
+	let mut client = Client { peer, ..Client::default() };
+	let mut negotiated = Encoding::Msgpack;
+	#[allow(clippy::result_large_err)] // the error type is tungstenite's, not ours
+	let callback = |req: &Request, mut resp: Response| {
+		if let Some(port) = port {
+			check_host(req, port)?;
+		}
+
+		client.browser_origin = req.headers().get("origin")
+			.and_then(|origin| origin.to_str().ok())
+			.map(String::from);
+
+		let offered: Vec<&str> = req.headers().get_all("sec-websocket-protocol").iter()
+			.filter_map(|protocols| protocols.to_str().ok())
+			.flat_map(|protocols| protocols.split(','))
+			.map(str::trim)
+			.collect();
+
+		if offered.is_empty() {
+			return Ok(resp);
+		}
+
+		// first one we speak wins, the client lists them in order of preference
+		let Some((protocol, version, encoding)) = offered.iter().find_map(|&protocol| {
+			Encoding::parse_subprotocol(protocol)
+				.filter(|(version, _)| SUPPORTED_PROTOCOL_VERSIONS.contains(version))
+				.map(|(version, encoding)| (protocol, version, encoding))
+		}) else {
+			log::error!("none of the offered subprotocols {offered:?} are supported");
+			return Err(unsupported_subprotocols());
+		};
+
+		(client.protocol_version, negotiated) = (version, encoding);
+		// the client offered a list, so it needs to hear which one it got
+		if let Ok(protocol) = protocol.parse() {
+			resp.headers_mut().insert("sec-websocket-protocol", protocol);
+		}
+
+		Ok(resp)
+	};
+
+	let ws = match tokio_tungstenite::accept_hdr_async(stream, callback).await {
+		Ok(ws) => ws,
+		Err(e) => {
+			log::error!("websocket handshake failed: {e}");
+			return;
+		}
+	};
+	log::debug!("connected to {client:?} in {negotiated:?}");
+
+	let client = Arc::new(client);
+	let (mut sink, mut stream) = ws.split();
+
+	// requests with an id run concurrently, so their responses all funnel through here
+	let (resp_tx, mut resp_rx) = mpsc::unbounded_channel::<Message>();
+	let writer = tokio::spawn(async move {
+		while let Some(msg) = resp_rx.recv().await {
+			// a failed send means the client went away, which the read loop will notice on its own
+			if let Err(e) = sink.send(msg).await {
+				log::error!("failed to send response: {e}");
+				break;
+			}
+		}
+	});
+
+	let in_flight = Arc::new(Semaphore::new(MAX_IN_FLIGHT));
+
+	while let Some(Ok(msg)) = stream.next().await {
+		let Some(encoding) = Encoding::of(&msg, negotiated) else { continue };
+
+		let (id, msg) = match decode_request(encoding, &msg.into_data()) {
+			Ok(request) => request,
+			Err((id, e)) => {
+				log::error!("malformed message: {e}");
+				send_resp(&resp_tx, encoding, id, &Resp::Error(Error::new(ErrorCode::MalformedRequest, format!("failed to decode message: {e}"))));
+				continue;
+			}
+		};
+
+		match id {
+			// without an id the client can't tell responses apart, so keep them in order
+			None => {
+				let resp = handle_msg(msg, &selected_backend, &client).await;
+				send_resp(&resp_tx, encoding, None, &resp);
+			},
+			Some(id) => {
+				let Ok(permit) = Arc::clone(&in_flight).acquire_owned().await else { break };
+				let (selected_backend, client, resp_tx) = (Arc::clone(&selected_backend), Arc::clone(&client), resp_tx.clone());
+				tokio::spawn(async move {
+					let resp = handle_msg(msg, &selected_backend, &client).await;
+					send_resp(&resp_tx, encoding, Some(id), &resp);
+					drop(permit);
+				});
+			}
+		}
+	}
+
+	// lets the writer finish once the last running request has answered
+	drop(resp_tx);
+	let _ = writer.await;
+
+	log::debug!("connection closed");
+}
+
+// tells the client what it could have asked for instead
+fn unsupported_subprotocols() -> ErrorResponse {
+	let supported: Vec<String> = SUPPORTED_PROTOCOL_VERSIONS.iter()
+		.flat_map(|&version| Encoding::ALL.map(|encoding| encoding.subprotocol(version)))
+		.collect();
+
+	reject(StatusCode::BAD_REQUEST, format!("unsupported subprotocol, supported are: {}", supported.join(", ")))
+}
+
+#[allow(clippy::result_large_err)] // the error type is tungstenite's, not ours
+fn check_host(req: &Request, expected_port: u16) -> Result<(), ErrorResponse> {
+	let host = req.headers().get("host").and_then(|host| host.to_str().ok()).unwrap_or_default();
+	let (name, port) = match host.rsplit_once(':') {
+		// the colons in `[::1]` aren't a port separator
+		Some((name, port)) if !port.ends_with(']') => (name, Some(port)),
+		_ => (host, None)
+	};
+
+	let port_ok = port.is_none_or(|port| port.parse() == Ok(expected_port));
+	if !LOOPBACK_HOSTS.iter().any(|loopback| loopback.eq_ignore_ascii_case(name)) || !port_ok {
+		log::error!("rejecting handshake for host {host:?}");
+		return Err(reject(StatusCode::FORBIDDEN, "unexpected host"));
+	}
+
+	Ok(())
+}
+
+fn reject(status: StatusCode, message: impl Into<String>) -> ErrorResponse {
+	let mut resp = ErrorResponse::new(Some(message.into()));
+	*resp.status_mut() = status;
+	resp
+}
+
+// on failure, still hands back the id if there was one, so the error can be matched to the request
+fn decode_request(encoding: Encoding, bytes: &[u8]) -> Result<(Option<u64>, Msg), (Option<u64>, String)> {
+	// a bare `Msg` is a string or a single-entry map keyed by its name, so it never has an id
+	match encoding.decode::<RequestId>(bytes) {
+		Ok(RequestId { id, .. }) => encoding.decode(bytes)
+			.map(|IdRequest { id, msg }| (Some(id), msg))
+			.map_err(|e| (Some(id), e)),
+		Err(_) => encoding.decode(bytes)
+			.map(|msg| (None, msg))
+			.map_err(|e| (None, e))
+	}
+}
+
+async fn handle_msg(msg: Msg, selected_backend: &SelectedBackend, client: &Client) -> Resp {
+	match msg {
+		Msg::Hello => Resp::Hello(HelloResp {
+			protocol_version: client.protocol_version,
+			backend: selected_backend.backend_type(),
+			curves: SUPPORTED_CURVES.iter().map(|c| c.to_string()).collect(),
+			consent_prompts: config::get().consent.method != config::ConsentMethod::None
+		}),
+		Msg::Register(mut sign_msg) => {
+			// the relying party can't do anything with a new key without the public half
+			sign_msg.include_key = true;
+			sign(selected_backend, client, sign_msg, Some(SignMode::Register)).await
+		},
+		// version 1 clients expect a key to be made for them the first time
+		Msg::Sign(sign_msg) if client.protocol_version < 2 => sign(selected_backend, client, sign_msg, None).await,
+		Msg::Authenticate(sign_msg) | Msg::Sign(sign_msg) => sign(selected_backend, client, sign_msg, Some(SignMode::Authenticate)).await,
+		Msg::GetPublicKey { origin } => {
+			let origin = match client.check_origin(&origin) {
+				Ok(origin) => origin,
+				Err(e) => return Resp::Error(e)
+			};
+
+			match selected_backend.get_public_key(origin).await {
+				Ok(ec_point) => Resp::PublicKey(ec_point),
+				Err(e) => {
+					log::error!("failed to get public key: {e}");
+					Resp::Error(e)
+				}
+			}
+		},
+		Msg::ListKeys => {
+			// otherwise any page could find out where else the user has accounts
+			if client.browser_origin.is_some() {
+				log::error!("refusing to list keys for {client:?}");
+				return Resp::Error(Error::new(ErrorCode::Forbidden, "web pages may not list keys"));
+			}
+
+			if let Err(e) = client.check_trusted() {
+				return Resp::Error(e);
+			}
+
+			match selected_backend.list_keys().await {
+				Ok(keys) => Resp::Keys(keys),
+				Err(e) => {
+					log::error!("failed to list keys: {e}");
+					Resp::Error(e)
 				}
 			}
+		},
+		Msg::BeginPairing { name } => {
+			// pages only get their own origin anyway, so there's no point letting them show codes unless they have to pair
+			if client.browser_origin.is_some() && !config::get().require_pairing {
+				log::error!("refusing to pair {client:?}");
+				return Resp::Error(Error::new(ErrorCode::Forbidden, "web pages can only pair when require_pairing is set in config.toml"));
+			}
+
+			match pairing::begin(&client.pairing, name, client.to_string(), client.requester()) {
+				Ok(()) => Resp::PairingStarted,
+				Err(e) => Resp::Error(e)
+			}
+		},
+		Msg::Pair { code } => match pairing::complete(&client.pairing, code, client.browser_origin.clone()).await {
+			Ok(token) => Resp::Paired { token },
+			Err(e) => {
+				log::error!("failed to pair: {e}");
+				Resp::Error(e)
+			}
+		},
+		Msg::Login { token } => match pairing::login(&client.pairing, token, client.browser_origin.clone()).await {
+			Ok(()) => Resp::LoggedIn,
+			Err(e) => {
+				log::error!("failed to log in: {e}");
+				Resp::Error(e)
+			}
+		},
+		Msg::DeleteKey { origin } => {
+			let origin = match client.check_origin(&origin) {
+				Ok(origin) => origin,
+				Err(e) => return Resp::Error(e)
+			};
+
+			// don't ask about a key that isn't there
+			if let Err(e) = selected_backend.get_public_key(origin.clone()).await {
+				return Resp::Error(e);
+			}
+
+			let prompt = consent::Prompt {
+				client: client.to_string(),
+				grantee: client.grantee(),
+				origin: origin.clone(),
+				action: consent::Action::Delete,
+				display: None,
+				data_sha256: None,
+				// a delete can't be undone, so it always needs someone to say yes
+				required: true,
+				peer: client.peer.clone()
+			};
+			if let Err(e) = consent::ask(&prompt).await {
+				return Resp::Error(e);
+			}
+
+			if let Err(e) = selected_backend.delete_key(origin.clone()).await {
+				log::error!("failed to delete key: {e}");
+				return Resp::Error(e);
+			}
+
+			// a new key for the origin should be asked about afresh
+			if let Err(e) = consent::revoke(origin).await {
+				log::error!("failed to revoke consent: {e}");
+			}
+			Resp::Deleted
+		},
+		Msg::RevokeConsent { origin } => {
+			let origin = match client.check_origin(&origin) {
+				Ok(origin) => origin,
+				Err(e) => return Resp::Error(e)
+			};
+
+			match consent::revoke(origin).await {
+				Ok(()) => Resp::Revoked,
+				Err(e) => {
+					log::error!("failed to revoke consent: {e}");
+					Resp::Error(e)
+				}
+			}
+		}
+	}
+}
+
+// `mode` is `None` for whichever one the origin needs, registering if it has no key yet
+async fn sign(selected_backend: &SelectedBackend, client: &Client, mut sign_msg: SignMsg, mode: Option<SignMode>) -> Resp {
+	sign_msg.origin = match client.check_origin(&sign_msg.origin) {
+		Ok(origin) => origin,
+		Err(e) => return Resp::Error(e)
+	};
+
+	// every key is P-256 for now
+	if let Err(e) = policy::check_curve(&sign_msg.origin, SUPPORTED_CURVES[0]) {
+		return Resp::Error(e);
+	}
+
+	if let Err(e) = ratelimit::check(&client.rate_limit, &sign_msg.origin) {
+		return Resp::Error(e);
+	}
+
+	if sign_msg.display.as_ref().is_some_and(|display| display.len() > MAX_DISPLAY_LEN) {
+		return Resp::Error(Error::new(ErrorCode::MalformedRequest, format!("display can be at most {MAX_DISPLAY_LEN} bytes")));
+	}
+
+	let mode = match mode {
+		Some(mode) => mode,
+		None => match selected_backend.get_public_key(sign_msg.origin.clone()).await {
+			Ok(_) => SignMode::Authenticate,
+			Err(e) if e.code == ErrorCode::KeyNotFound => SignMode::Register,
+			Err(e) => {
+				log::error!("failed to look up key: {e}");
+				return Resp::Error(e);
+			}
+		}
+	};
+
+	if let Some(display) = &sign_msg.display {
+		sign_msg.data = bind_display(display, &sign_msg.data);
+		if sign_msg.data.len() > MAX_BOUND_LEN {
+			let max = MAX_BOUND_LEN - DISPLAY_PREFIX.len() - 4;
+			return Resp::Error(Error::new(ErrorCode::MalformedRequest, format!("display and data can be at most {max} bytes together")));
 		}
 	}
+
+	let prompt = consent::Prompt {
+		client: client.to_string(),
+		grantee: client.grantee(),
+		origin: sign_msg.origin.clone(),
+		action: match mode {
+			SignMode::Register => consent::Action::Register,
+			SignMode::Authenticate => consent::Action::Authenticate
+		},
+		display: sign_msg.display.clone(),
+		// the same hash every backend signs, so a command can log or match exactly what it let through
+		data_sha256: Some(Sha256::digest(&sign_msg.data).into()),
+		required: policy::requires_consent(&sign_msg.origin),
+		peer: client.peer.clone()
+	};
+	if let Err(e) = consent::ask(&prompt).await {
+		return Resp::Error(e);
+	}
+
+	// only what the user agreed to counts, so a burst of refused prompts doesn't lock an origin out
+	if let Err(e) = policy::count_signature(&sign_msg.origin) {
+		return Resp::Error(e);
+	}
+
+	match selected_backend.sign(sign_msg, mode).await {
+		Ok(sign_resp) => Resp::Sign(sign_resp),
+		Err(e) => {
+			log::error!("failed to sign: {e}");
+			Resp::Error(e)
+		}
+	}
+}
+
+// the signature is over this rather than `data`, so a relying party that rebuilds it knows exactly what text the user was shown
+// the prefix keeps it from ever being mistaken for plain `data` without a display
+fn bind_display(display: &str, data: &[u8]) -> Vec<u8> {
+	let mut bound = Vec::with_capacity(DISPLAY_PREFIX.len() + 4 + display.len() + data.len());
+	bound.extend_from_slice(DISPLAY_PREFIX);
+	bound.extend_from_slice(&(display.len() as u32).to_be_bytes());
+	bound.extend_from_slice(display.as_bytes());
+	bound.extend_from_slice(data);
+	bound
+}
+
+fn send_resp(resp_tx: &mpsc::UnboundedSender<Message>, encoding: Encoding, id: Option<u64>, resp: &Resp) {
+	let encoded = match id {
+		Some(id) => encoding.encode(&IdResp { id, resp }),
+		None => encoding.encode(resp)
+	};
+
+	let msg = match encoded {
+		Ok(msg) => msg,
+		Err(e) => {
+			log::error!("failed to encode response: {e}");
+			return;
+		}
+	};
+
+	// only fails once the writer has given up on the connection
+	let _ = resp_tx.send(msg);
+}
+
+// lets a client have several requests running and match up the responses, which may come back in any order
+#[derive(Deserialize)]
+struct IdRequest {
+	id: u64,
+	msg: Msg
+}
+
+// just the id of an `IdRequest`, for when the rest of it doesn't decode
+#[derive(Deserialize)]
+struct RequestId {
+	id: u64,
+	#[allow(dead_code)]
+	msg: IgnoredAny
+}
+
+#[derive(Serialize)]
+struct IdResp<'a> {
+	id: u64,
+	resp: &'a Resp
 }
 
 #[derive(Deserialize)]
 enum Msg {
-	Sign(SignMsg)
+	// protocol version 1 name for `Authenticate`, which used to create missing keys
+	Sign(SignMsg),
+	Hello,
+	GetPublicKey {
+		origin: String
+	},
+	Register(SignMsg),
+	Authenticate(SignMsg),
+	ListKeys,
+	DeleteKey {
+		origin: String
+	},
+	// forgets every remembered yes for the origin, so the user gets asked again
+	RevokeConsent {
+		origin: String
+	},
+	// shows a code at the daemon's terminal, which the user then gives to the client for `Pair`
+	BeginPairing {
+		name: String
+	},
+	Pair {
+		code: String
+	},
+	// for every connection after the one that paired
+	Login {
+		#[serde(with = "encoding::bytes")]
+		token: Vec<u8>
+	}
 }
 
 #[derive(Deserialize)]
 struct SignMsg {
 	origin: String,
+	#[serde(with = "encoding::bytes")]
 	data: Vec<u8>,
-	include_key: bool
+	include_key: bool,
+	// shown to the user when asking, and bound into what gets signed, see `bind_display`
+	#[serde(default)]
+	display: Option<String>
 }
 
 #[derive(Serialize)]
 enum Resp {
 	Sign(SignResp),
-	Error(String)
+	Hello(HelloResp),
+	PublicKey(EcPoint),
+	Keys(Vec<KeyInfo>),
+	Deleted,
+	Revoked,
+	PairingStarted,
+	Paired {
+		#[serde(with = "encoding::bytes")]
+		token: Vec<u8>
+	},
+	LoggedIn,
+	Error(Error)
+}
+
+#[derive(Serialize)]
+struct HelloResp {
+	protocol_version: u32,
+	backend: BackendType,
+	curves: Vec<String>,
+	consent_prompts: bool
+}
+
+#[derive(Serialize, Debug, Clone, Copy)]
+enum BackendType {
+	Tpm,
+	Pkcs11,
+	Software
 }
 
 #[derive(Serialize)]
 struct SignResp {
+	#[serde(with = "encoding::bytes")]
 	sig_r: Vec<u8>,
+	#[serde(with = "encoding::bytes")]
 	sig_s: Vec<u8>,
 	ec_point: Option<EcPoint>
 }
 
+#[derive(Serialize)]
+struct KeyInfo {
+	origin: String,
+	backend: BackendType,
+	// `None` if the key exists but its public half couldn't be read
+	ec_point: Option<EcPoint>
+}
+
 #[derive(Serialize)]
 struct EcPoint {
+	#[serde(with = "encoding::bytes")]
 	x: Vec<u8>,
+	#[serde(with = "encoding::bytes")]
 	y: Vec<u8>
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::collections::HashMap;
+
+	#[test]
+	fn hosts() {
+		let cases = [
+			(Some("localhost"), true),
+			(Some("localhost:8000"), true),
+			(Some("LocalHost:8000"), true),
+			(Some("127.0.0.1:8000"), true),
+			(Some("[::1]"), true),
+			(Some("[::1]:8000"), true),
+			(Some("localhost:8001"), false),
+			(Some("localhost:"), false),
+			(Some("localhost:08000x"), false),
+			(Some("[::1]:8443"), false),
+			(Some("evil.com"), false),
+			(Some("evil.com:8000"), false),
+			(Some("localhost.evil.com:8000"), false),
+			(Some("127.0.0.2:8000"), false),
+			(Some("::1"), false),
+			(Some(""), false),
+			(None, false)
+		];
+
+		for (host, allowed) in cases {
+			let mut req = Request::builder();
+			if let Some(host) = host {
+				req = req.header("host", host);
+			}
+			assert_eq!(check_host(&req.body(()).unwrap(), PORT).is_ok(), allowed, "{host:?}");
+		}
+	}
+
+	#[test]
+	fn request_ids() {
+		let get_public_key = HashMap::from([("GetPublicKey", ("https://example.com",))]);
+
+		let bare = rmp_serde::to_vec(&"Hello").unwrap();
+		assert!(matches!(decode_request(Encoding::Msgpack, &bare), Ok((None, Msg::Hello))));
+
+		let bare = rmp_serde::to_vec(&get_public_key).unwrap();
+		assert!(matches!(decode_request(Encoding::Msgpack, &bare), Ok((None, Msg::GetPublicKey { origin })) if origin == "https://example.com"));
+
+		let with_id = rmp_serde::to_vec(&(7, "Hello")).unwrap();
+		assert!(matches!(decode_request(Encoding::Msgpack, &with_id), Ok((Some(7), Msg::Hello))));
+
+		let with_id = rmp_serde::to_vec(&(u64::MAX, &get_public_key)).unwrap();
+		assert!(matches!(decode_request(Encoding::Msgpack, &with_id), Ok((Some(u64::MAX), Msg::GetPublicKey { .. }))));
+
+		// the id still comes back when the message can't be decoded
+		let bad_msg = rmp_serde::to_vec(&(7, "Goodbye")).unwrap();
+		assert!(matches!(decode_request(Encoding::Msgpack, &bad_msg), Err((Some(7), _))));
+
+		let bad_bare = rmp_serde::to_vec(&"Goodbye").unwrap();
+		assert!(matches!(decode_request(Encoding::Msgpack, &bad_bare), Err((None, _))));
+
+		assert!(matches!(decode_request(Encoding::Msgpack, &[0xc1]), Err((None, _))));
+
+		let json_cases = [
+			(r#""Hello""#, Some(None)),
+			(r#"{"GetPublicKey": {"origin": "https://example.com"}}"#, Some(None)),
+			(r#"{"id": 7, "msg": "Hello"}"#, Some(Some(7))),
+			(r#"{"msg": "ListKeys", "id": 8}"#, Some(Some(8))),
+			(r#"{"id": 7, "msg": "Goodbye"}"#, None),
+			(r#""Goodbye""#, None),
+			(r#"{"id": "7", "msg": "Hello"}"#, None),
+			("", None)
+		];
+
+		for (json, expected) in json_cases {
+			match (decode_request(Encoding::Json, json.as_bytes()), expected) {
+				(Ok((id, _)), Some(expected)) => assert_eq!(id, expected, "{json}"),
+				(Err(_), None) => (),
+				(result, _) => panic!("{json} decoded to {:?}", result.map(|(id, _)| id))
+			}
+		}
+		assert!(matches!(decode_request(Encoding::Json, br#"{"id": 7, "msg": "Goodbye"}"#), Err((Some(7), _))));
+	}
+
+	#[test]
+	fn json_bytes() {
+		// padded or not, as javascript's helpers disagree
+		for data in [r#""-_8""#, r#""-_8=""#] {
+			let json = format!(r#"{{"origin": "https://example.com", "data": {data}, "include_key": true}}"#);
+			let msg: SignMsg = Encoding::Json.decode(json.as_bytes()).unwrap();
+			assert_eq!(msg.data, [0xfb, 0xff], "{data}");
+		}
+		assert!(Encoding::Json.decode::<SignMsg>(br#"{"origin": "https://example.com", "data": "+/8", "include_key": true}"#).is_err());
+		assert!(Encoding::Json.decode::<SignMsg>(br#"{"origin": "https://example.com", "data": [251, 255], "include_key": true}"#).is_err());
+
+		let resp = SignResp {
+			sig_r: vec![0xfb, 0xff],
+			sig_s: vec![1],
+			ec_point: Some(EcPoint { x: vec![2], y: vec![] })
+		};
+		let Ok(Message::Text(json)) = Encoding::Json.encode(&resp) else { panic!() };
+		assert_eq!(json, r#"{"sig_r":"-_8","sig_s":"AQ","ec_point":{"x":"Ag","y":""}}"#);
+
+		// msgpack clients keep getting arrays of numbers
+		let Ok(Message::Binary(msgpack)) = Encoding::Msgpack.encode(&resp) else { panic!() };
+		assert_eq!(msgpack, rmp_serde::to_vec(&([0xfbu8, 0xff], [1u8], Some(([2u8], [0u8; 0])))).unwrap());
+		let msg: SignMsg = Encoding::Msgpack.decode(&rmp_serde::to_vec(&("https://example.com", [0xfbu8, 0xff], false)).unwrap()).unwrap();
+		assert_eq!(msg.data, [0xfb, 0xff]);
+	}
+
+	#[test]
+	fn cbor_bytes() {
+		use ciborium::Value;
+
+		let resp = SignResp {
+			sig_r: vec![0xfb, 0xff],
+			sig_s: vec![1],
+			ec_point: Some(EcPoint { x: vec![2], y: vec![] })
+		};
+		let Ok(Message::Binary(cbor)) = Encoding::Cbor.encode(&resp) else { panic!() };
+		let expected = Value::Map(vec![
+			(Value::Text("sig_r".into()), Value::Bytes(vec![0xfb, 0xff])),
+			(Value::Text("sig_s".into()), Value::Bytes(vec![1])),
+			(Value::Text("ec_point".into()), Value::Map(vec![
+				(Value::Text("x".into()), Value::Bytes(vec![2])),
+				(Value::Text("y".into()), Value::Bytes(vec![]))
+			]))
+		]);
+		assert_eq!(ciborium::de::from_reader::<Value, _>(cbor.as_slice()).unwrap(), expected);
+
+		// either a byte string or an array of numbers
+		for data in [Value::Bytes(vec![0xfb, 0xff]), Value::Array(vec![Value::from(0xfb), Value::from(0xff)])] {
+			let msg = Value::Map(vec![
+				(Value::Text("origin".into()), Value::Text("https://example.com".into())),
+				(Value::Text("data".into()), data),
+				(Value::Text("include_key".into()), Value::Bool(true))
+			]);
+			let mut encoded = Vec::new();
+			ciborium::ser::into_writer(&msg, &mut encoded).unwrap();
+			let msg: SignMsg = Encoding::Cbor.decode(&encoded).unwrap();
+			assert_eq!(msg.data, [0xfb, 0xff]);
+		}
+
+		// and msgpack byte strings are taken too, though never sent
+		let mut encoded = vec![0x93, 0xb3];
+		encoded.extend(b"https://example.com");
+		encoded.extend([0xc4, 2, 0xfb, 0xff, 0xc2]);
+		let msg: SignMsg = Encoding::Msgpack.decode(&encoded).unwrap();
+		assert_eq!(msg.data, [0xfb, 0xff]);
+	}
+
+	#[test]
+	fn bound_display() {
+		let cases: [(&str, &[u8], &[u8]); 3] = [
+			("", b"", b"bunker display v1\0\0\0\0\0"),
+			("Pay \u{a3}10", b"\x01\x02", b"bunker display v1\0\0\0\0\x08Pay \xc2\xa310\x01\x02"),
+			// the length keeps where the display ends from being moved into the data
+			("ab", b"c", b"bunker display v1\0\0\0\0\x02abc")
+		];
+
+		for (display, data, bound) in cases {
+			assert_eq!(bind_display(display, data), bound, "{display:?}");
+		}
+		assert_ne!(bind_display("ab", b"c"), bind_display("a", b"bc"));
+
+		// the longest display still leaves room for a challenge on every backend
+		assert!(bind_display(&"a".repeat(MAX_DISPLAY_LEN), &[0; 64]).len() <= MAX_BOUND_LEN);
+	}
+}
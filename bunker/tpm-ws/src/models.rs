@@ -30,3 +30,37 @@ pub struct NewSoftwareKey {
 	pub encrypted_private_key_iv: Vec<u8>,
 	pub private_key_sha3_512_sum: Vec<u8>
 }
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::clients)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct NewClient {
+	pub name: String,
+	pub token_sha3_256: Vec<u8>,
+	pub browser_origin: Option<String>,
+	pub created_at: i64
+}
+
+// `expires_at` being `None` is forever, so it has to be written as NULL rather than skipped on update
+#[derive(Insertable, AsChangeset)]
+#[diesel(table_name = crate::schema::consent_grants, treat_none_as_null = true)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct NewConsentGrant {
+	pub origin: String,
+	pub grantee: String,
+	pub expires_at: Option<i64>,
+	pub created_at: i64
+}
+
+#[derive(Insertable, AsChangeset)]
+#[diesel(table_name = crate::schema::tls_certificates)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct NewTlsCertificate {
+	pub name: String,
+	pub certificate: Vec<u8>,
+	pub encrypted_private_key: Vec<u8>,
+	pub encrypted_private_key_iv: Vec<u8>,
+	pub private_key_sha3_512_sum: Vec<u8>,
+	pub expires_at: i64,
+	pub created_at: i64
+}
@@ -10,22 +10,30 @@ tpm-ws is distributed in the hope that it will be useful, but WITHOUT ANY WARRAN
 You should have received a copy of the GNU General Public License along with tpm-ws. If not, see <https://www.gnu.org/licenses/>.
 */
 
-use tokio_tungstenite::WebSocketStream;
-use tokio::io::{AsyncRead, AsyncWrite};
-use crate::{Backend, SignMsg};
+use crate::{Backend, KeyInfo, SignMode, SignMsg, SignResp, EcPoint};
+use crate::error::{Error, ErrorCode};
 
 #[derive(Default, Debug)]
-pub struct TpmBackend;
+pub struct TpmBackend {}
 
 impl Backend for TpmBackend {
 	fn is_supported() -> bool {
 		false
 	}
 
-	async fn sign_msg<S>(&self, _ws: &mut WebSocketStream<S>, _sign_msg: SignMsg)
-	where
-		S: AsyncRead + AsyncWrite + Unpin
-	{
-		unimplemented!()
+	async fn sign(&self, _sign_msg: SignMsg, _mode: SignMode) -> Result<SignResp, Error> {
+		Err(Error::new(ErrorCode::BackendUnavailable, "built without tpm support"))
+	}
+
+	async fn get_public_key(&self, _origin: String) -> Result<EcPoint, Error> {
+		Err(Error::new(ErrorCode::BackendUnavailable, "built without tpm support"))
+	}
+
+	async fn list_keys(&self) -> Result<Vec<KeyInfo>, Error> {
+		Err(Error::new(ErrorCode::BackendUnavailable, "built without tpm support"))
+	}
+
+	async fn delete_key(&self, _origin: String) -> Result<(), Error> {
+		Err(Error::new(ErrorCode::BackendUnavailable, "built without tpm support"))
 	}
 }
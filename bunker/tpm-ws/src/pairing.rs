@@ -0,0 +1,312 @@
+/*
+Copyright James Connolly 2024
+
+This file is part of tpm-ws.
+
+tpm-ws is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+
+tpm-ws is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with tpm-ws. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::task::spawn_blocking;
+use rand_core::{OsRng, RngCore};
+use diesel::{QueryDsl, RunQueryDsl, ExpressionMethods, OptionalExtension};
+use sha3::{Sha3_256, Digest};
+use subtle::ConstantTimeEq;
+use crate::db;
+use crate::error::{Error, ErrorCode};
+
+const CODE_DIGITS: u32 = 6;
+const CODE_LIFETIME: Duration = Duration::from_secs(120);
+// a million codes and three guesses is about as good as the odds of guessing a pin
+const CODE_ATTEMPTS: u32 = 3;
+// wrong codes that can count against one requester at a time, each for an hour
+const MAX_FAILURES: usize = 10;
+const FAILURE_LIFETIME: Duration = Duration::from_secs(60 * 60);
+const TOKEN_LEN: usize = 32;
+// has to match the CHECK on `clients.name`
+const MAX_NAME_LEN: usize = 100;
+
+// shared by every connection, so opening new ones doesn't buy more codes or guesses
+static LIMITS: Mutex<Limits> = Mutex::new(Limits::new());
+
+// kept per requester, which is something a client can't change by reconnecting, like a page's origin
+// so running out of guesses only shuts out whoever made them
+#[derive(Debug, Default)]
+struct Limits(BTreeMap<String, Requester>);
+
+#[derive(Debug, Default)]
+struct Requester {
+	// only one code can be out at a time, and one that ran out of attempts still counts until it would have expired
+	pending_until: Option<Instant>,
+	// when each wrong code stops counting
+	failures: Vec<Instant>
+}
+
+impl Limits {
+	const fn new() -> Self {
+		Limits(BTreeMap::new())
+	}
+
+	fn begin(&mut self, requester: &str, now: Instant) -> Result<(), Error> {
+		// requesters with nothing that still counts behave the same as new ones
+		self.0.retain(|_, r| {
+			r.failures.retain(|&until| until > now);
+			r.pending_until.is_some_and(|until| until > now) || !r.failures.is_empty()
+		});
+
+		let r = self.0.entry(requester.to_string()).or_default();
+		if r.failures.len() >= MAX_FAILURES {
+			return Err(Error::new(ErrorCode::PairingFailed, "too many wrong codes, try again later"));
+		}
+		if r.pending_until.is_some_and(|until| until > now) {
+			return Err(Error::new(ErrorCode::PairingFailed, "another pairing is in progress, try again later"));
+		}
+
+		r.pending_until = Some(now + CODE_LIFETIME);
+		Ok(())
+	}
+
+	// `true` once the requester is out of guesses
+	fn fail(&mut self, requester: &str, now: Instant) -> bool {
+		let r = self.0.entry(requester.to_string()).or_default();
+		r.failures.retain(|&until| until > now);
+		r.failures.push(now + FAILURE_LIFETIME);
+		r.failures.len() >= MAX_FAILURES
+	}
+
+	// the code is used up, so the requester can start another right away
+	fn succeed(&mut self, requester: &str) {
+		if let Some(r) = self.0.get_mut(requester) {
+			r.pending_until = None;
+		}
+	}
+}
+
+// per connection, a client pairs or logs in once and stays that way until it disconnects
+#[derive(Debug, Default)]
+pub struct Session {
+	pending: Option<PendingPairing>,
+	// the row in `clients` and the name it paired under
+	paired: Option<(i32, String)>
+}
+
+struct PendingPairing {
+	name: String,
+	requester: String,
+	code: String,
+	expires: Instant,
+	attempts_left: u32
+}
+
+// clients get logged, and the code mustn't end up anywhere but the terminal
+impl std::fmt::Debug for PendingPairing {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("PendingPairing")
+			.field("name", &self.name)
+			.field("requester", &self.requester)
+			.field("expires", &self.expires)
+			.field("attempts_left", &self.attempts_left)
+			.finish_non_exhaustive()
+	}
+}
+
+impl Session {
+	pub fn paired(&self) -> Option<(i32, &str)> {
+		self.paired.as_ref().map(|(id, name)| (*id, name.as_str()))
+	}
+}
+
+// `who` is only for the user's benefit, so they know what they're pairing
+pub fn begin(session: &Mutex<Session>, name: String, who: String, requester: String) -> Result<(), Error> {
+	if name.is_empty() || name.len() > MAX_NAME_LEN {
+		return Err(Error::new(ErrorCode::MalformedRequest, format!("name must be 1 to {MAX_NAME_LEN} bytes")));
+	}
+
+	LIMITS.lock().unwrap().begin(&requester, Instant::now())
+		.inspect_err(|e| log::error!("refusing to pair {who}: {e}"))?;
+
+	let code = random_code();
+	// the name is the client's to choose, so escape it rather than let it write to the terminal
+	eprintln!("{who} wants to pair as {name:?}, the code is {code}");
+	log::info!("started pairing {who} as {name:?}");
+
+	session.lock().unwrap().pending = Some(PendingPairing {
+		name,
+		requester,
+		code,
+		expires: Instant::now() + CODE_LIFETIME,
+		attempts_left: CODE_ATTEMPTS
+	});
+
+	Ok(())
+}
+
+// hands back the token the client has to log in with from now on, we only keep its hash
+pub async fn complete(session: &Mutex<Session>, code: String, browser_origin: Option<String>) -> Result<Vec<u8>, Error> {
+	let name = check_code(&mut session.lock().unwrap(), &code, &mut LIMITS.lock().unwrap(), Instant::now())?;
+
+	let mut token = vec![0; TOKEN_LEN];
+	OsRng.fill_bytes(&mut token);
+
+	let new_client = crate::models::NewClient {
+		name: name.clone(),
+		token_sha3_256: Sha3_256::digest(&token).to_vec(),
+		browser_origin,
+		created_at: db::now()
+	};
+
+	let id = spawn_blocking(move || {
+		use crate::schema::clients::dsl;
+
+		let mut conn = db::get_conn()?;
+		let token_sha3_256 = new_client.token_sha3_256.clone();
+		diesel::insert_into(dsl::clients).values(new_client).execute(&mut conn)?;
+		Ok::<i32, Error>(dsl::clients.filter(dsl::token_sha3_256.eq(token_sha3_256)).select(dsl::id).first(&mut conn)?)
+	}).await??;
+
+	log::info!("paired {name:?}");
+	session.lock().unwrap().paired = Some((id, name));
+
+	Ok(token)
+}
+
+// a page's token only works for the origin that paired it, so another site can't use one it got hold of
+pub async fn login(session: &Mutex<Session>, token: Vec<u8>, browser_origin: Option<String>) -> Result<(), Error> {
+	let token_sha3_256 = Sha3_256::digest(&token).to_vec();
+
+	let found: Option<(i32, String, Option<String>)> = spawn_blocking(move || {
+		use crate::schema::clients::dsl;
+
+		let mut conn = db::get_conn()?;
+		Ok::<_, Error>(dsl::clients.filter(dsl::token_sha3_256.eq(token_sha3_256))
+			.select((dsl::id, dsl::name, dsl::browser_origin)).first(&mut conn).optional()?)
+	}).await??;
+
+	match found {
+		Some((id, name, paired_origin)) if paired_origin == browser_origin => {
+			log::debug!("logged in as {name:?}");
+			session.lock().unwrap().paired = Some((id, name));
+			Ok(())
+		},
+		_ => Err(Error::new(ErrorCode::Unauthenticated, "unknown token"))
+	}
+}
+
+// hands back the name to pair under if the code is right
+fn check_code(session: &mut Session, code: &str, limits: &mut Limits, now: Instant) -> Result<String, Error> {
+	let pending = session.pending.as_mut()
+		.filter(|pending| pending.expires > now)
+		.ok_or_else(|| Error::new(ErrorCode::PairingFailed, "no pairing in progress, or it expired"))?;
+
+	if !bool::from(pending.code.as_bytes().ct_eq(code.as_bytes())) {
+		pending.attempts_left -= 1;
+		if limits.fail(&pending.requester, now) || pending.attempts_left == 0 {
+			session.pending = None;
+		}
+		return Err(Error::new(ErrorCode::PairingFailed, "wrong code"));
+	}
+
+	let pending = session.pending.take().unwrap();
+	limits.succeed(&pending.requester);
+	Ok(pending.name)
+}
+
+fn random_code() -> String {
+	let modulus = 10u32.pow(CODE_DIGITS);
+	// skips the top few values that would make low codes slightly more likely
+	let limit = u32::MAX - u32::MAX % modulus;
+	let code = loop {
+		let n = OsRng.next_u32();
+		if n < limit {
+			break n % modulus;
+		}
+	};
+
+	format!("{code:0width$}", width = CODE_DIGITS as usize)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn pending(code: &str, requester: &str, now: Instant) -> Session {
+		Session {
+			pending: Some(PendingPairing {
+				name: "app".to_string(),
+				requester: requester.to_string(),
+				code: code.to_string(),
+				expires: now + CODE_LIFETIME,
+				attempts_left: CODE_ATTEMPTS
+			}),
+			paired: None
+		}
+	}
+
+	#[test]
+	fn codes() {
+		for _ in 0..100 {
+			let code = random_code();
+			assert_eq!(code.len(), CODE_DIGITS as usize);
+			assert!(code.bytes().all(|b| b.is_ascii_digit()), "{code}");
+		}
+	}
+
+	#[test]
+	fn attempts() {
+		let mut limits = Limits::new();
+		let now = Instant::now();
+
+		let mut session = pending("123456", "local app", now);
+		assert_eq!(check_code(&mut session, "123456", &mut limits, now).unwrap(), "app");
+		assert!(session.pending.is_none());
+		assert_eq!(check_code(&mut session, "123456", &mut limits, now).unwrap_err().code, ErrorCode::PairingFailed);
+
+		// the last wrong code takes the code with it, even though the right one comes after
+		let mut session = pending("123456", "local app", now);
+		for _ in 0..CODE_ATTEMPTS {
+			assert!(check_code(&mut session, "654321", &mut limits, now).is_err());
+		}
+		assert!(session.pending.is_none());
+		assert!(check_code(&mut session, "123456", &mut limits, now).is_err());
+
+		let mut session = pending("123456", "local app", now);
+		assert!(check_code(&mut session, "123456", &mut limits, now + CODE_LIFETIME).is_err());
+		assert!(check_code(&mut session, "12345", &mut limits, now).is_err());
+		assert!(check_code(&mut session, "1234567", &mut limits, now).is_err());
+		assert_eq!(check_code(&mut session, "123456", &mut limits, now).unwrap(), "app");
+	}
+
+	#[test]
+	fn limits() {
+		let mut limits = Limits::new();
+		let now = Instant::now();
+
+		// one code at a time, until it's used or would have expired
+		limits.begin("page https://example.com", now).unwrap();
+		assert!(limits.begin("page https://example.com", now + CODE_LIFETIME / 2).is_err());
+		limits.begin("page https://example.com", now + CODE_LIFETIME).unwrap();
+		limits.succeed("page https://example.com");
+		limits.begin("page https://example.com", now + CODE_LIFETIME).unwrap();
+
+		// running out of guesses shuts out nobody else
+		let mut session = pending("123456", "page https://evil.com", now);
+		for i in 0..MAX_FAILURES {
+			if session.pending.is_none() {
+				session = pending("123456", "page https://evil.com", now);
+			}
+			assert!(check_code(&mut session, &format!("{i:06}"), &mut limits, now).is_err());
+		}
+		assert!(session.pending.is_none());
+		assert!(limits.begin("page https://evil.com", now + CODE_LIFETIME).is_err());
+		limits.begin("local app", now).unwrap();
+
+		// and wrong codes stop counting after a while
+		assert!(limits.begin("page https://evil.com", now + FAILURE_LIFETIME).is_ok());
+	}
+}
@@ -10,27 +10,35 @@ tpm-ws is distributed in the hope that it will be useful, but WITHOUT ANY WARRAN
 You should have received a copy of the GNU General Public License along with tpm-ws. If not, see <https://www.gnu.org/licenses/>.
 */
 
-use futures::sink::SinkExt;
-use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::task::spawn_blocking;
-use tokio_tungstenite::WebSocketStream;
-use tokio_tungstenite::tungstenite::protocol::Message;
+use tokio::sync::Mutex;
 use cryptoki::context::{Pkcs11, CInitializeArgs};
 use cryptoki::mechanism::Mechanism;
-use cryptoki::object::{Attribute, AttributeType};
-use cryptoki::session::UserType;
+use cryptoki::object::{Attribute, AttributeType, ObjectClass, ObjectHandle};
+use cryptoki::session::{Session, UserType};
 use cryptoki::types::AuthPin;
 use cryptoki::slot::Slot;
 use std::path::PathBuf;
 use std::str::FromStr;
-use crate::{Backend, Resp, SignMsg, SignResp, EcPoint};
+use std::sync::Arc;
+use crate::{Backend, BackendType, KeyInfo, SignMode, SignMsg, SignResp, EcPoint};
+use crate::error::{Error, ErrorCode};
 
 // TODO: store these behind secret service/windows credential manager
 const SO_PIN: &str = "1234";
 const USER_PIN: &str = "0000";
 
+impl From<cryptoki::error::Error> for Error {
+	fn from(e: cryptoki::error::Error) -> Self {
+		Error::new(ErrorCode::Internal, format!("pkcs#11 error: {e}"))
+	}
+}
+
 #[derive(Default, Debug)]
-pub struct Pkcs11Backend;
+pub struct Pkcs11Backend {
+	// every request initializes and finalizes the library, which can't overlap
+	lock: Arc<Mutex<()>>
+}
 
 impl Backend for Pkcs11Backend {
 	fn is_supported() -> bool {
@@ -41,36 +49,69 @@ impl Backend for Pkcs11Backend {
 		}
 	}
 
-	async fn sign_msg<S>(&self, ws: &mut WebSocketStream<S>, sign_msg: SignMsg)
-	where
-		S: AsyncRead + AsyncWrite + Unpin
-	{
-		let sign_resp = spawn_blocking(|| sign(sign_msg)).await.unwrap();
-		let msg = rmp_serde::to_vec(&Resp::Sign(sign_resp)).unwrap();
-		ws.send(Message::Binary(msg)).await.unwrap();
+	async fn sign(&self, sign_msg: SignMsg, mode: SignMode) -> Result<SignResp, Error> {
+		let guard = Arc::clone(&self.lock).lock_owned().await;
+		spawn_blocking(move || {
+			let _guard = guard;
+			with_session(|session| sign(session, sign_msg, mode))
+		}).await?
+	}
+
+	async fn get_public_key(&self, origin: String) -> Result<EcPoint, Error> {
+		let guard = Arc::clone(&self.lock).lock_owned().await;
+		spawn_blocking(move || {
+			let _guard = guard;
+			with_session(|session| {
+				let (pub_id, _) = key_ids(&origin);
+				let pub_key = find_object(session, pub_id)?
+					.ok_or_else(|| Error::key_not_found(&origin))?;
+				ec_point(session, pub_key)
+			})
+		}).await?
 	}
+
+	async fn list_keys(&self) -> Result<Vec<KeyInfo>, Error> {
+		let guard = Arc::clone(&self.lock).lock_owned().await;
+		spawn_blocking(move || {
+			let _guard = guard;
+			with_session(list_keys)
+		}).await?
+	}
+
+	async fn delete_key(&self, origin: String) -> Result<(), Error> {
+		let guard = Arc::clone(&self.lock).lock_owned().await;
+		spawn_blocking(move || {
+			let _guard = guard;
+			with_session(|session| delete_key(session, &origin))
+		}).await?
+	}
+}
+
+fn login_error(e: cryptoki::error::Error) -> Error {
+	Error::new(ErrorCode::Pkcs11LoginFailed, format!("failed to log in to pkcs#11 token: {e}"))
 }
 
-fn get_slot(pkcs11: &Pkcs11) -> Slot {
-	let mut slots = pkcs11.get_slots_with_token().unwrap();
+fn get_slot(pkcs11: &Pkcs11) -> Result<Slot, Error> {
+	let slots = pkcs11.get_slots_with_token()?;
 	log::debug!("slots: {slots:?}");
 
 	for slot in &slots {
-		let token_info = pkcs11.get_token_info(*slot).unwrap();
+		let token_info = pkcs11.get_token_info(*slot)?;
 
 		if token_info.label() == "tpm-ws" {
-			return *slot;
+			return Ok(*slot);
 		}
 	}
 
-	let slot = slots.remove(0);
-	pkcs11.init_token(slot, &AuthPin::from_str(SO_PIN).unwrap(), "tpm-ws").unwrap();
+	let slot = *slots.first()
+		.ok_or_else(|| Error::new(ErrorCode::BackendUnavailable, "no pkcs#11 token available"))?;
+	pkcs11.init_token(slot, &AuthPin::from_str(SO_PIN).unwrap(), "tpm-ws")?;
 
-	let session = pkcs11.open_rw_session(slot).unwrap();
-	session.login(UserType::So, Some(&AuthPin::from_str(SO_PIN).unwrap())).unwrap();
-	session.init_pin(&AuthPin::from_str(USER_PIN).unwrap()).unwrap();
+	let session = pkcs11.open_rw_session(slot)?;
+	session.login(UserType::So, Some(&AuthPin::from_str(SO_PIN).unwrap())).map_err(login_error)?;
+	session.init_pin(&AuthPin::from_str(USER_PIN).unwrap())?;
 
-	slot
+	Ok(slot)
 }
 
 #[cfg(target_os = "linux")]
@@ -83,63 +124,144 @@ fn get_pkcs11_impl() -> Option<PathBuf> {
 	None
 }
 
-fn sign(sign_msg: SignMsg) -> SignResp {
-	let pkcs11 = Pkcs11::new(get_pkcs11_impl().expect("checked earlier")).unwrap();
-	pkcs11.initialize(CInitializeArgs::OsThreads).unwrap();
+fn with_session<T>(f: impl FnOnce(&Session) -> Result<T, Error>) -> Result<T, Error> {
+	let path = get_pkcs11_impl()
+		.ok_or_else(|| Error::new(ErrorCode::BackendUnavailable, "no pkcs#11 implementation on this platform"))?;
+	let pkcs11 = Pkcs11::new(path)
+		.map_err(|e| Error::new(ErrorCode::BackendUnavailable, format!("failed to load pkcs#11 implementation: {e}")))?;
+	pkcs11.initialize(CInitializeArgs::OsThreads)
+		.map_err(|e| Error::new(ErrorCode::BackendUnavailable, format!("failed to initialize pkcs#11: {e}")))?;
 
-	let slot = get_slot(&pkcs11);
+	let slot = get_slot(&pkcs11)?;
 
-	let session = pkcs11.open_rw_session(slot).unwrap();
-	session.login(UserType::User, Some(&AuthPin::from_str(USER_PIN).unwrap())).unwrap();
+	let session = pkcs11.open_rw_session(slot)?;
+	session.login(UserType::User, Some(&AuthPin::from_str(USER_PIN).unwrap())).map_err(login_error)?;
 
-	let (pub_id, priv_id) = (
-		format!("auth-{}-pub", &sign_msg.origin).into_bytes(),
-		format!("auth-{}-priv", &sign_msg.origin).into_bytes()
-	);
+	f(&session)
+}
+
+fn key_ids(origin: &str) -> (Vec<u8>, Vec<u8>) {
+	(
+		format!("auth-{origin}-pub").into_bytes(),
+		format!("auth-{origin}-priv").into_bytes()
+	)
+}
 
-	let mut found = session.find_objects(&[Attribute::Id(priv_id.clone())]).unwrap();
+fn find_object(session: &Session, id: Vec<u8>) -> Result<Option<ObjectHandle>, Error> {
+	let mut found = session.find_objects(&[Attribute::Id(id.clone())])?;
 	if found.len() > 1 {
-		panic!("should have only found zero or one key with id {priv_id:?}");
+		return Err(Error::new(ErrorCode::Internal, format!("found more than one object with id {}", String::from_utf8_lossy(&id))));
 	}
 
-	let (pub_key, priv_key) = if let Some(priv_key) = found.pop() {
-		log::debug!("found previously generated private key");
-		let mut found = session.find_objects(&[Attribute::Id(pub_id)]).unwrap();
-		if found.len() != 1 {
-			panic!("failed to find corresponding public key");
+	Ok(found.pop())
+}
+
+fn ec_point(session: &Session, pub_key: ObjectHandle) -> Result<EcPoint, Error> {
+	match session.get_attributes(pub_key, &[AttributeType::EcPoint])?.pop() {
+		Some(Attribute::EcPoint(p)) => decode_ec_point(&p),
+		_ => Err(Error::new(ErrorCode::Internal, "failed to extract public key info"))
+	}
+}
+
+// DER octet string wrapping an uncompressed sec1 point
+fn decode_ec_point(p: &[u8]) -> Result<EcPoint, Error> {
+	if p.len() != 67 {
+		return Err(Error::new(ErrorCode::Internal, "failed to extract public key info"));
+	}
+
+	let x = p[3..35].to_vec();
+	let y = p[35..67].to_vec();
+
+	Ok(EcPoint { x, y })
+}
+
+fn list_keys(session: &Session) -> Result<Vec<KeyInfo>, Error> {
+	let mut keys = Vec::new();
+
+	for pub_key in session.find_objects(&[Attribute::Class(ObjectClass::PUBLIC_KEY)])? {
+		let mut id = None;
+		let mut ec_point = None;
+		for attribute in session.get_attributes(pub_key, &[AttributeType::Id, AttributeType::EcPoint])? {
+			match attribute {
+				Attribute::Id(i) => id = Some(i),
+				Attribute::EcPoint(p) => ec_point = decode_ec_point(&p).ok(),
+				_ => ()
+			}
 		}
 
-		(found.pop().unwrap(), priv_key)
-	} else {
-		// P-256 curve (hopefully not NSA backdoored?)
-		let ec_params = Attribute::EcParams(vec![0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07]);
+		// skip anything on the token that we didn't make
+		let origin = id.as_deref()
+			.and_then(|id| std::str::from_utf8(id).ok())
+			.and_then(|id| id.strip_prefix("auth-"))
+			.and_then(|id| id.strip_suffix("-pub"));
+
+		if let Some(origin) = origin {
+			keys.push(KeyInfo {
+				origin: origin.to_string(),
+				backend: BackendType::Pkcs11,
+				ec_point
+			});
+		}
+	}
+
+	keys.sort_by(|a, b| a.origin.cmp(&b.origin));
+	Ok(keys)
+}
+
+fn sign(session: &Session, sign_msg: SignMsg, mode: SignMode) -> Result<SignResp, Error> {
+	let (pub_id, priv_id) = key_ids(&sign_msg.origin);
+
+	let (pub_key, priv_key) = match (find_object(session, priv_id.clone())?, mode) {
+		(Some(priv_key), SignMode::Authenticate) => {
+			log::debug!("found previously generated private key");
+			let pub_key = find_object(session, pub_id)?
+				.ok_or_else(|| Error::new(ErrorCode::Internal, format!("failed to find public key for {}", sign_msg.origin)))?;
+			(pub_key, priv_key)
+		},
+		(Some(_), SignMode::Register) => return Err(Error::key_exists(&sign_msg.origin)),
+		(None, SignMode::Authenticate) => return Err(Error::key_not_found(&sign_msg.origin)),
+		(None, SignMode::Register) => {
+			// P-256 curve (hopefully not NSA backdoored?)
+			let ec_params = Attribute::EcParams(vec![0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07]);
 
-		session.generate_key_pair(&Mechanism::EccKeyPairGen,
-			&[Attribute::Token(true), Attribute::Extractable(true), Attribute::Id(pub_id), ec_params],
-			&[Attribute::Token(true), Attribute::Extractable(false), Attribute::Id(priv_id)]).unwrap()
+			session.generate_key_pair(&Mechanism::EccKeyPairGen,
+				&[Attribute::Token(true), Attribute::Extractable(true), Attribute::Id(pub_id), ec_params],
+				&[Attribute::Token(true), Attribute::Extractable(false), Attribute::Id(priv_id)])?
+		}
 	};
 
-	let signed = session.sign(&Mechanism::EcdsaSha256, priv_key, &sign_msg.data).unwrap();
+	let signed = session.sign(&Mechanism::EcdsaSha256, priv_key, &sign_msg.data)?;
+	if signed.len() != 64 {
+		return Err(Error::new(ErrorCode::Internal, "token returned a malformed signature"));
+	}
 	let sig_r = signed[0..32].to_vec();
 	let sig_s = signed[32..64].to_vec();
 
 	let ec_point = if sign_msg.include_key {
-		if let Attribute::EcPoint(p) = session.get_attributes(pub_key, &[AttributeType::EcPoint]).unwrap().pop().unwrap() {
-			assert_eq!(p.len(), 67);
-			let x = p[3..35].to_vec();
-			let y = p[35..67].to_vec();
-
-			Some(EcPoint { x, y })
-		} else {
-			panic!("failed to extract public key info");
-		}
+		Some(ec_point(session, pub_key)?)
 	} else {
 		None
 	};
 
-	SignResp {
+	Ok(SignResp {
 		sig_r,
 		sig_s,
 		ec_point
+	})
+}
+
+fn delete_key(session: &Session, origin: &str) -> Result<(), Error> {
+	let (pub_id, priv_id) = key_ids(origin);
+	let (pub_key, priv_key) = (find_object(session, pub_id)?, find_object(session, priv_id)?);
+
+	if pub_key.is_none() && priv_key.is_none() {
+		return Err(Error::key_not_found(origin));
 	}
+
+	// private half first, a leftover public key is harmless
+	for key in [priv_key, pub_key].into_iter().flatten() {
+		session.destroy_object(key)?;
+	}
+
+	Ok(())
 }
@@ -0,0 +1,310 @@
+/*
+Copyright James Connolly 2024
+
+This file is part of tpm-ws.
+
+tpm-ws is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+
+tpm-ws is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with tpm-ws. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::sync::OnceLock;
+use crate::config::{self, Limit, PolicyDefault};
+use crate::error::{Error, ErrorCode};
+use crate::origin;
+use crate::ratelimit::Buckets;
+use crate::unix::Peer;
+
+static POLICY: OnceLock<Policy> = OnceLock::new();
+
+// per origin, for `max_signatures_per_minute`
+static SIGNATURES: Buckets = Buckets::new();
+
+// the `[policy]` part of the config, with its origins canonicalized so they compare like the ones in requests
+#[derive(Debug)]
+struct Policy {
+	default: PolicyDefault,
+	deny: Vec<Pattern>,
+	rules: Vec<Rule>
+}
+
+#[derive(Debug)]
+struct Rule {
+	origins: Vec<Pattern>,
+	clients: Option<Vec<ClientPattern>>,
+	require_consent: bool,
+	curves: Option<Vec<String>>,
+	max_signatures_per_minute: Option<u32>
+}
+
+#[derive(Debug)]
+struct Pattern {
+	// empty for https, like canonical origins
+	scheme: String,
+	host_port: String,
+	// matches subdomains of `host_port`, not `host_port` itself
+	subdomains: bool
+}
+
+#[derive(Debug)]
+enum ClientPattern {
+	Page,
+	Local,
+	// any paired app if there's no name
+	Paired(Option<String>),
+	// connections over the unix socket
+	Uid(u32)
+}
+
+// who is asking, as far as rules can tell
+#[derive(Debug)]
+pub struct Who {
+	pub kind: ClientKind,
+	// only for connections over the unix socket
+	pub peer: Option<Peer>
+}
+
+#[derive(Debug)]
+pub enum ClientKind {
+	Page,
+	Local,
+	// under the name it paired as
+	Paired(String)
+}
+
+impl Pattern {
+	fn parse(pattern: &str) -> Result<Self, Error> {
+		let (scheme, rest) = match pattern.split_once("://") {
+			Some((scheme, rest)) => (Some(scheme), rest),
+			None => (None, pattern)
+		};
+		let (subdomains, rest) = match rest.strip_prefix("*.") {
+			Some(rest) => (true, rest),
+			None => (false, rest)
+		};
+
+		// host names can't have one, so anywhere else it would just never match
+		if rest.contains('*') {
+			return Err(Error::new(ErrorCode::InvalidOrigin, "`*` only works as a leading `*.`"));
+		}
+
+		let canonical = match scheme {
+			Some(scheme) => origin::canonicalize(&format!("{scheme}://{rest}"))?,
+			None => origin::canonicalize(rest)?
+		};
+		let (scheme, host_port) = split_scheme(&canonical);
+
+		Ok(Pattern {
+			scheme: scheme.to_string(),
+			host_port: host_port.to_string(),
+			subdomains
+		})
+	}
+
+	// `origin` has to be canonical already
+	fn matches(&self, origin: &str) -> bool {
+		let (scheme, host_port) = split_scheme(origin);
+		if scheme != self.scheme {
+			return false;
+		}
+
+		if self.subdomains {
+			host_port.strip_suffix(&self.host_port).is_some_and(|sub| sub.len() > 1 && sub.ends_with('.'))
+		} else {
+			host_port == self.host_port
+		}
+	}
+}
+
+impl ClientPattern {
+	fn parse(pattern: &str) -> Option<Self> {
+		match pattern.split_once(':') {
+			Some(("paired", name)) => Some(ClientPattern::Paired(Some(name.to_string()))),
+			Some(("uid", uid)) => uid.parse().ok().map(ClientPattern::Uid),
+			Some(_) => None,
+			None => match pattern {
+				"page" => Some(ClientPattern::Page),
+				"local" => Some(ClientPattern::Local),
+				"paired" => Some(ClientPattern::Paired(None)),
+				_ => None
+			}
+		}
+	}
+
+	fn matches(&self, who: &Who) -> bool {
+		match (self, &who.kind) {
+			(ClientPattern::Page, ClientKind::Page) | (ClientPattern::Local, ClientKind::Local) => true,
+			(ClientPattern::Paired(None), ClientKind::Paired(_)) => true,
+			(ClientPattern::Paired(Some(name)), ClientKind::Paired(paired_as)) => name == paired_as,
+			(ClientPattern::Uid(uid), _) => who.peer.as_ref().is_some_and(|peer| peer.uid == *uid),
+			_ => false
+		}
+	}
+}
+
+// canonical origins only have a scheme when it isn't https
+fn split_scheme(origin: &str) -> (&str, &str) {
+	origin.split_once("://").unwrap_or(("", origin))
+}
+
+// a policy that doesn't make sense panics, like the rest of the config
+pub fn load() {
+	let config = &config::get().policy;
+
+	let patterns = |patterns: &[String]| -> Vec<Pattern> {
+		patterns.iter()
+			.map(|pattern| Pattern::parse(pattern).unwrap_or_else(|e| panic!("invalid origin {pattern:?} in policy: {e}")))
+			.collect()
+	};
+
+	let rules = config.rules.iter().map(|rule| {
+		if let Some(curve) = rule.curves.iter().flatten().find(|curve| !crate::SUPPORTED_CURVES.contains(&curve.as_str())) {
+			panic!("unsupported curve {curve:?} in policy, expected one of {:?}", crate::SUPPORTED_CURVES);
+		}
+
+		if rule.max_signatures_per_minute == Some(0) {
+			panic!("max_signatures_per_minute is 0 in policy, use deny to refuse an origin instead");
+		}
+
+		Rule {
+			origins: patterns(&rule.origins),
+			clients: rule.clients.as_ref().map(|clients| clients.iter()
+				.map(|client| ClientPattern::parse(client).unwrap_or_else(|| panic!("invalid client {client:?} in policy, expected \"page\", \"local\", \"paired\", \"paired:<name>\" or \"uid:<uid>\"")))
+				.collect()),
+			require_consent: rule.require_consent,
+			curves: rule.curves.clone(),
+			max_signatures_per_minute: rule.max_signatures_per_minute
+		}
+	}).collect();
+
+	let policy = Policy {
+		default: config.default,
+		deny: patterns(&config.deny),
+		rules
+	};
+
+	log::debug!("loaded {policy:?}");
+	POLICY.set(policy).expect("policy loaded twice");
+}
+
+// the rule that applies to a canonical origin, if any does
+fn rule(origin: &str) -> Option<&'static Rule> {
+	let policy = POLICY.get().expect("policy used before it was loaded");
+	policy.rules.iter().find(|rule| rule.origins.iter().any(|pattern| pattern.matches(origin)))
+}
+
+// whether `who` may use the key for a canonical origin at all
+pub fn check(origin: &str, who: &Who) -> Result<(), Error> {
+	let policy = POLICY.get().expect("policy used before it was loaded");
+	let forbidden = |why: &str| {
+		log::error!("policy refuses {who:?} the key for {origin}: {why}");
+		Err(Error::new(ErrorCode::Forbidden, format!("policy doesn't allow using the key for {origin}: {why}")))
+	};
+
+	if policy.deny.iter().any(|pattern| pattern.matches(origin)) {
+		return forbidden("the origin is denied");
+	}
+
+	match rule(origin) {
+		Some(Rule { clients: Some(clients), .. }) if !clients.iter().any(|client| client.matches(who)) => forbidden("not for this client"),
+		Some(_) => Ok(()),
+		None if policy.default == PolicyDefault::Deny => forbidden("no rule allows the origin"),
+		None => Ok(())
+	}
+}
+
+// keys can only be made or used with the curves the origin's rule allows
+pub fn check_curve(origin: &str, curve: &str) -> Result<(), Error> {
+	match rule(origin) {
+		Some(Rule { curves: Some(curves), .. }) if !curves.iter().any(|allowed| allowed == curve) => {
+			log::error!("policy refuses {curve} for {origin}");
+			Err(Error::new(ErrorCode::Forbidden, format!("policy doesn't allow {curve} keys for {origin}")))
+		},
+		_ => Ok(())
+	}
+}
+
+// counts a signature towards the origin's limit, unless it's already reached
+pub fn count_signature(origin: &str) -> Result<(), Error> {
+	let Some(max) = rule(origin).and_then(|rule| rule.max_signatures_per_minute) else {
+		return Ok(());
+	};
+
+	// all of them at once is fine, as long as that's all there are in the minute
+	if !SIGNATURES.take(origin, Limit { per_minute: max, burst: max }) {
+		log::warn!("{origin} has already signed {max} times in the last minute");
+		return Err(Error::new(ErrorCode::RateLimited, format!("policy allows {origin} at most {max} signatures a minute")));
+	}
+
+	Ok(())
+}
+
+// whether the user has to be asked every time, even if there's no way to
+pub fn requires_consent(origin: &str) -> bool {
+	rule(origin).is_some_and(|rule| rule.require_consent)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn patterns() {
+		let cases = [
+			("example.com", "example.com", true),
+			("example.com", "https://Example.com:443", true),
+			("https://example.com", "example.com", true),
+			("example.com", "www.example.com", false),
+			("example.com", "http://example.com", false),
+			("example.com", "example.com:8443", false),
+			("http://example.com", "http://example.com", true),
+			("http://example.com", "example.com", false),
+			("*.example.com", "www.example.com", true),
+			("*.example.com", "a.b.example.com", true),
+			("*.example.com", "example.com", false),
+			("*.example.com", "evilexample.com", false),
+			("*.example.com", "www.example.com.evil.com", false),
+			("*.example.com", "http://www.example.com", false),
+			("http://*.example.com", "http://www.example.com", true),
+			("http://*.example.com", "www.example.com", false),
+			("*.bücher.de", "www.xn--bcher-kva.de", true),
+			("localhost:3000", "localhost:3000", true),
+			("localhost:3000", "localhost", false)
+		];
+
+		for (pattern, origin, matches) in cases {
+			let origin = origin::canonicalize(origin).unwrap();
+			assert_eq!(Pattern::parse(pattern).unwrap().matches(&origin), matches, "{pattern} {origin}");
+		}
+	}
+
+	#[test]
+	fn invalid_patterns() {
+		for pattern in ["", "*", "*.", "example.com/path", "*.*.example.com", "example.*.com"] {
+			assert!(Pattern::parse(pattern).is_err(), "{pattern}");
+		}
+	}
+	#[test]
+	fn clients() {
+		let peer = |uid| Some(Peer { uid, pid: Some(1), exe: Some("/usr/bin/ssh".into()) });
+		let who = |kind, peer| Who { kind, peer };
+
+		let uid = ClientPattern::parse("uid:1000").unwrap();
+		assert!(uid.matches(&who(ClientKind::Local, peer(1000))));
+		assert!(uid.matches(&who(ClientKind::Paired("ci".to_string()), peer(1000))));
+		assert!(!uid.matches(&who(ClientKind::Local, peer(1001))));
+		assert!(!uid.matches(&who(ClientKind::Local, None)));
+
+		let paired = ClientPattern::parse("paired:ci").unwrap();
+		assert!(paired.matches(&who(ClientKind::Paired("ci".to_string()), None)));
+		assert!(!paired.matches(&who(ClientKind::Paired("cd".to_string()), None)));
+		assert!(!paired.matches(&who(ClientKind::Local, None)));
+
+		// the executable is only a hint, so rules can't be written against it
+		for pattern in ["exe:/usr/bin/ssh", "uid:", "uid:me", "pages"] {
+			assert!(ClientPattern::parse(pattern).is_none(), "{pattern}");
+		}
+	}
+}
@@ -0,0 +1,159 @@
+/*
+Copyright James Connolly 2024
+
+This file is part of tpm-ws.
+
+tpm-ws is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+
+tpm-ws is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with tpm-ws. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+use crate::config::{self, Limit};
+use crate::error::{Error, ErrorCode};
+
+static ORIGINS: Buckets = Buckets::new();
+
+// past this many keys, buckets that have filled back up get dropped, since they'd behave the same as new ones
+const MAX_IDLE_BUCKETS: usize = 1000;
+
+// a token bucket, which starts full and refills at the limit's rate
+// the limit is passed in each time rather than kept, so the config can stay the one place it lives
+#[derive(Debug, Default)]
+pub struct Bucket {
+	tokens: f64,
+	// `None` until first used, when the bucket is full
+	last: Option<Instant>
+}
+
+impl Bucket {
+	// `true` and one token fewer if there was one to take
+	pub fn take(&mut self, limit: Limit) -> bool {
+		if limit.per_minute == 0 {
+			return true;
+		}
+
+		let now = Instant::now();
+		self.refill(limit, now);
+		self.last = Some(now);
+
+		if self.tokens < 1.0 {
+			return false;
+		}
+
+		self.tokens -= 1.0;
+		true
+	}
+
+	fn refill(&mut self, limit: Limit, now: Instant) {
+		let burst = limit.burst as f64;
+		self.tokens = match self.last {
+			Some(last) => (self.tokens + now.duration_since(last).as_secs_f64() * limit.per_minute as f64 / 60.0).min(burst),
+			None => burst
+		};
+	}
+
+	fn is_full(&self, limit: Limit, now: Instant) -> bool {
+		let Some(last) = self.last else {
+			return true;
+		};
+
+		self.tokens + now.duration_since(last).as_secs_f64() * limit.per_minute as f64 / 60.0 >= limit.burst as f64
+	}
+}
+
+// a bucket per key, e.g. per origin, all with the same limit
+#[derive(Debug)]
+pub struct Buckets(Mutex<Option<HashMap<String, Bucket>>>);
+
+impl Buckets {
+	pub const fn new() -> Self {
+		Buckets(Mutex::new(None))
+	}
+
+	pub fn take(&self, key: &str, limit: Limit) -> bool {
+		if limit.per_minute == 0 {
+			return true;
+		}
+
+		let mut buckets = self.0.lock().unwrap();
+		let buckets = buckets.get_or_insert_with(HashMap::new);
+
+		if buckets.len() >= MAX_IDLE_BUCKETS {
+			let now = Instant::now();
+			buckets.retain(|_, bucket| !bucket.is_full(limit, now));
+		}
+
+		buckets.entry(key.to_string()).or_default().take(limit)
+	}
+}
+
+// for each signing request, before anyone gets asked about it, so a page looping on them can't flood the user or the tpm
+pub fn check(connection: &Mutex<Bucket>, origin: &str) -> Result<(), Error> {
+	let config = &config::get().rate_limit;
+
+	if !connection.lock().unwrap().take(config.connection) {
+		log::warn!("too many signing requests on one connection");
+		return Err(Error::new(ErrorCode::RateLimited, "too many signing requests on this connection, slow down"));
+	}
+
+	if !ORIGINS.take(origin, config.origin) {
+		log::warn!("too many signing requests for {origin}");
+		return Err(Error::new(ErrorCode::RateLimited, format!("too many signing requests for {origin}, slow down")));
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::time::Duration;
+
+	const LIMIT: Limit = Limit { per_minute: 60, burst: 3 };
+
+	#[test]
+	fn bursts_then_refuses() {
+		let mut bucket = Bucket::default();
+		let taken: Vec<bool> = (0..5).map(|_| bucket.take(LIMIT)).collect();
+		assert_eq!(taken, [true, true, true, false, false]);
+	}
+
+	#[test]
+	fn refills_at_the_rate() {
+		let cases = [
+			// seconds since the bucket was emptied, and how many can be taken then
+			(0, 0),
+			(1, 1),
+			(2, 2),
+			(30, 3)
+		];
+
+		for (secs, available) in cases {
+			let mut bucket = Bucket { tokens: 0.0, last: Instant::now().checked_sub(Duration::from_secs(secs)) };
+			let taken = (0..5).filter(|_| bucket.take(LIMIT)).count();
+			assert_eq!(taken, available, "after {secs}s");
+		}
+	}
+
+	#[test]
+	fn zero_is_unlimited() {
+		let mut bucket = Bucket::default();
+		assert!((0..1000).all(|_| bucket.take(Limit { per_minute: 0, burst: 0 })));
+
+		let buckets = Buckets::new();
+		assert!((0..1000).all(|_| buckets.take("example.com", Limit { per_minute: 0, burst: 0 })));
+	}
+
+	#[test]
+	fn keys_are_separate() {
+		let buckets = Buckets::new();
+		assert!((0..3).all(|_| buckets.take("a.com", LIMIT)));
+		assert!(!buckets.take("a.com", LIMIT));
+		assert!(buckets.take("b.com", LIMIT));
+	}
+}
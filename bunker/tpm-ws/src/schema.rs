@@ -1,5 +1,25 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    clients (id) {
+        id -> Integer,
+        name -> Text,
+        token_sha3_256 -> Binary,
+        browser_origin -> Nullable<Text>,
+        created_at -> BigInt,
+    }
+}
+
+diesel::table! {
+    consent_grants (id) {
+        id -> Integer,
+        origin -> Text,
+        grantee -> Text,
+        expires_at -> Nullable<BigInt>,
+        created_at -> BigInt,
+    }
+}
+
 diesel::table! {
     software_keys (id) {
         id -> Integer,
@@ -19,7 +39,23 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    tls_certificates (id) {
+        id -> Integer,
+        name -> Text,
+        certificate -> Binary,
+        encrypted_private_key -> Binary,
+        encrypted_private_key_iv -> Binary,
+        private_key_sha3_512_sum -> Binary,
+        expires_at -> BigInt,
+        created_at -> BigInt,
+    }
+}
+
 diesel::allow_tables_to_appear_in_same_query!(
+    clients,
+    consent_grants,
     software_keys,
+    tls_certificates,
     tpm_keys,
 );
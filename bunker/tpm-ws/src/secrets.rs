@@ -14,16 +14,17 @@ use rand_core::OsRng;
 use zeroize::Zeroizing;
 use aes_gcm::{Aes256Gcm, KeyInit};
 use tokio::task::spawn_blocking;
+use crate::error::{Error, ErrorCode};
 
 #[cfg(feature = "tpm")]
-async fn generate_random_password() -> Zeroizing<[u8; 32]> {
+async fn generate_random_password() -> Result<Zeroizing<[u8; 32]>, Error> {
 	use rand_core::RngCore;
 
-	spawn_blocking(|| {
+	Ok(spawn_blocking(|| {
 		let mut password = Zeroizing::new([0u8; 32]);
 		OsRng.fill_bytes(password.as_mut());
 		password
-	}).await.unwrap()
+	}).await?)
 }
 
 fn generate_aes_key_blocking() -> Zeroizing<[u8; 32]> {
@@ -32,165 +33,321 @@ fn generate_aes_key_blocking() -> Zeroizing<[u8; 32]> {
 }
 
 #[cfg(target_os = "linux")]
-async fn generate_aes_key() -> Zeroizing<[u8; 32]> {
-	spawn_blocking(generate_aes_key_blocking).await.unwrap()
+async fn generate_aes_key() -> Result<Zeroizing<[u8; 32]>, Error> {
+	Ok(spawn_blocking(generate_aes_key_blocking).await?)
 }
 
-#[cfg(all(feature = "tpm", target_os = "linux"))]
-pub async fn get_password(origin: &str) -> Zeroizing<Vec<u8>> {
-	use std::collections::HashMap;
+#[cfg(target_os = "linux")]
+async fn open_keyring() -> Result<oo7::Keyring, Error> {
+	let keyring = oo7::Keyring::new().await
+		.map_err(|e| Error::new(ErrorCode::BackendUnavailable, format!("failed to open keyring: {e}")))?;
+	keyring.unlock().await
+		.map_err(|e| Error::new(ErrorCode::KeyringLocked, format!("failed to unlock keyring: {e}")))?;
+
+	Ok(keyring)
+}
+
+#[cfg(target_os = "linux")]
+fn keyring_error(e: oo7::Error) -> Error {
+	Error::new(ErrorCode::Internal, format!("keyring error: {e}"))
+}
 
-	let keyring = oo7::Keyring::new().await.unwrap();
-	keyring.unlock().await.unwrap();
-	let attributes = HashMap::from([
+#[cfg(target_os = "linux")]
+fn attributes<'a>(origin: &'a str, kind: &'a str) -> std::collections::HashMap<&'a str, &'a str> {
+	std::collections::HashMap::from([
 		("tpm-ws_version", "0.1.0"),
 		("origin", origin),
-		("type", "tpm_password")
-	]);
+		("type", kind)
+	])
+}
 
-	let mut items = keyring.search_items(&attributes).await.unwrap();
+#[cfg(target_os = "linux")]
+async fn find_secret(keyring: &oo7::Keyring, origin: &str, kind: &str) -> Result<Option<Zeroizing<Vec<u8>>>, Error> {
+	let mut items = keyring.search_items(&attributes(origin, kind)).await.map_err(keyring_error)?;
 	match items.len() {
-		0 => {
-			let new_pass = generate_random_password().await;
-			keyring.create_item("origin_password", &attributes, &new_pass, true).await.unwrap();
-			// yes this is hacky, but that's why they call it a hackathon!
-			let mut v = Zeroizing::new(Vec::<u8>::with_capacity(32));
-			v.extend(new_pass.as_ref());
-			v
-		},
-		1 => items.remove(0).secret().await.unwrap(),
-		_ => panic!("should only have one password per origin")
+		0 => Ok(None),
+		1 => items.remove(0).secret().await.map(Some).map_err(keyring_error),
+		_ => Err(Error::new(ErrorCode::Internal, format!("found more than one {kind} for {origin}")))
 	}
 }
 
+// returns whether there was anything to delete
 #[cfg(target_os = "linux")]
-pub async fn get_aes_key(origin: &str) -> Zeroizing<Vec<u8>> {
-	use std::collections::HashMap;
+async fn delete_secret(origin: &str, kind: &str) -> Result<bool, Error> {
+	let keyring = open_keyring().await?;
+	let items = keyring.search_items(&attributes(origin, kind)).await.map_err(keyring_error)?;
+	for item in &items {
+		item.delete().await.map_err(keyring_error)?;
+	}
 
-	let keyring = oo7::Keyring::new().await.unwrap();
-	keyring.unlock().await.unwrap();
-	let attributes = HashMap::from([
-		("tpm-ws_version", "0.1.0"),
-		("origin", origin),
-		("type", "aes_key")
-	]);
+	Ok(!items.is_empty())
+}
 
-	let mut items = keyring.search_items(&attributes).await.unwrap();
-	match items.len() {
-		0 => {
-			let new_aes_key = generate_aes_key().await;
-			assert_eq!(new_aes_key.len(), 32);
-			keyring.create_item("origin_password", &attributes, &new_aes_key, true).await.unwrap();
-			// yeah yeah, silence
-			let mut v = Zeroizing::new(Vec::<u8>::with_capacity(32));
-			v.extend(new_aes_key.as_ref());
-			v
-		},
-		1 => items.remove(0).secret().await.unwrap(),
-		_ => panic!("should only have one password per origin")
+// unlike `get_password`, this never creates a password that doesn't exist yet
+#[cfg(all(feature = "tpm", target_os = "linux"))]
+pub async fn find_password(origin: &str) -> Result<Option<Zeroizing<Vec<u8>>>, Error> {
+	let keyring = open_keyring().await?;
+	find_secret(&keyring, origin, "tpm_password").await
+}
+
+#[cfg(all(feature = "tpm", target_os = "linux"))]
+pub async fn get_password(origin: &str) -> Result<Zeroizing<Vec<u8>>, Error> {
+	let keyring = open_keyring().await?;
+	if let Some(password) = find_secret(&keyring, origin, "tpm_password").await? {
+		return Ok(password);
+	}
+
+	let new_pass = generate_random_password().await?;
+	keyring.create_item("origin_password", &attributes(origin, "tpm_password"), &new_pass, true).await.map_err(keyring_error)?;
+	// yes this is hacky, but that's why they call it a hackathon!
+	let mut v = Zeroizing::new(Vec::<u8>::with_capacity(32));
+	v.extend(new_pass.as_ref());
+	Ok(v)
+}
+
+#[cfg(all(feature = "tpm", target_os = "linux"))]
+pub async fn delete_password(origin: &str) -> Result<bool, Error> {
+	delete_secret(origin, "tpm_password").await
+}
+
+// unlike `get_aes_key`, this never creates a key that doesn't exist yet
+#[cfg(target_os = "linux")]
+pub async fn find_aes_key(origin: &str) -> Result<Option<Zeroizing<Vec<u8>>>, Error> {
+	let keyring = open_keyring().await?;
+	find_secret(&keyring, origin, "aes_key").await
+}
+
+#[cfg(target_os = "linux")]
+pub async fn get_aes_key(origin: &str) -> Result<Zeroizing<Vec<u8>>, Error> {
+	let keyring = open_keyring().await?;
+	if let Some(aes_key) = find_secret(&keyring, origin, "aes_key").await? {
+		return Ok(aes_key);
+	}
+
+	let new_aes_key = generate_aes_key().await?;
+	assert_eq!(new_aes_key.len(), 32);
+	keyring.create_item("origin_password", &attributes(origin, "aes_key"), &new_aes_key, true).await.map_err(keyring_error)?;
+	// yeah yeah, silence
+	let mut v = Zeroizing::new(Vec::<u8>::with_capacity(32));
+	v.extend(new_aes_key.as_ref());
+	Ok(v)
+}
+
+#[cfg(target_os = "linux")]
+pub async fn delete_aes_key(origin: &str) -> Result<bool, Error> {
+	delete_secret(origin, "aes_key").await
+}
+
+// for moving a key to another origin, returns whether there was anything to copy
+// the same secret already under `to` is left by a move that stopped before deleting the old one, anything else is a collision
+#[cfg(target_os = "linux")]
+async fn copy_secret(from: &str, to: &str, kind: &str) -> Result<bool, Error> {
+	let keyring = open_keyring().await?;
+	let Some(secret) = find_secret(&keyring, from, kind).await? else {
+		return Ok(false);
+	};
+
+	match find_secret(&keyring, to, kind).await? {
+		Some(existing) if existing == secret => (),
+		Some(_) => return Err(Error::new(ErrorCode::KeyExists, format!("a different {kind} is already stored for {to}"))),
+		None => keyring.create_item("origin_password", &attributes(to, kind), &*secret, true).await.map_err(keyring_error)?
 	}
+
+	Ok(true)
+}
+
+#[cfg(all(feature = "tpm", target_os = "linux"))]
+pub async fn copy_password(from: &str, to: &str) -> Result<bool, Error> {
+	copy_secret(from, to, "tpm_password").await
+}
+
+#[cfg(target_os = "linux")]
+pub async fn copy_aes_key(from: &str, to: &str) -> Result<bool, Error> {
+	copy_secret(from, to, "aes_key").await
 }
 
 #[cfg(target_os = "windows")]
-pub async fn get_aes_key(origin: &str) -> Zeroizing<Vec<u8>> {
-	use std::ffi::CString;
+fn cred_name(origin: &str) -> Result<std::ffi::CString, Error> {
+	std::ffi::CString::new(format!("tpm-ws/{origin}"))
+		.map_err(|_| Error::new(ErrorCode::InvalidOrigin, "origin must not contain nul bytes"))
+}
+
+#[cfg(target_os = "windows")]
+fn read_credential(cred_name: &std::ffi::CStr) -> Result<Option<Zeroizing<Vec<u8>>>, Error> {
 	use core::ffi::c_void;
-	use windows::core::{PCSTR, PSTR};
-	use windows::Win32::Security::Credentials::{CredReadA, CredWriteA, CREDENTIALA, CRED_FLAGS, CRED_TYPE, CRED_PERSIST, CREDENTIAL_ATTRIBUTEA, CredFree};
+	use windows::core::PCSTR;
+	use windows::Win32::Security::Credentials::{CredReadA, CREDENTIALA, CRED_TYPE, CredFree};
+
+	let layout = std::alloc::Layout::new::<CREDENTIALA>();
+
+	// SAFETY: we must free this later
+	let mut cred_ptr = unsafe {
+		std::mem::transmute(std::alloc::alloc(layout))
+	};
+	let double_ptr: *mut *mut CREDENTIALA = &mut cred_ptr;
+
+	// SAFETY: if this is `Err(_)` then `cred_ptr` is probably a biohazard
+	let result = unsafe {
+		CredReadA(PCSTR::from_raw(cred_name.as_ptr() as *const u8), CRED_TYPE(1), 0, double_ptr)
+	};
+
+	if result.is_ok() {
+		let key = unsafe {
+			if (*cred_ptr).CredentialBlobSize == 32 {
+				Some(std::ptr::read_unaligned((*cred_ptr).CredentialBlob as *const [u8; 32]).to_vec())
+			} else {
+				None
+			}
+		};
+
+		std::mem::forget(result);
+		// SAFETY: `result` is no longer valid
+		unsafe {
+			CredFree(cred_ptr as *const c_void);
+		}
+
+		key.map(|key| Some(Zeroizing::new(key))).ok_or_else(|| Error::new(ErrorCode::Internal, "stored aes key is not 32 bytes"))
+	} else {
+		std::mem::forget(result);
+		// SAFETY: `result` is no longer valid
+		unsafe {
+			CredFree(cred_ptr as *const c_void);
+		}
+
+		Ok(None)
+	}
+}
+
+#[cfg(target_os = "windows")]
+fn write_credential(cred_name: &std::ffi::CStr, key: &[u8; 32]) -> Result<(), Error> {
+	use std::ffi::CString;
+	use windows::core::PSTR;
+	use windows::Win32::Security::Credentials::{CredWriteA, CREDENTIALA, CRED_FLAGS, CRED_TYPE, CRED_PERSIST, CREDENTIAL_ATTRIBUTEA};
 	use windows::Win32::System::SystemInformation::GetSystemTime;
 	use windows::Win32::System::Time::SystemTimeToFileTime;
 	use windows::Win32::Foundation::{SYSTEMTIME, FILETIME};
 
-	let cred_name = CString::new(format!("tpm-ws/{origin}")).unwrap();
+	let file_time_layout = std::alloc::Layout::new::<FILETIME>();
+	// SAFETY: we gotta free this
+	let file_time = unsafe {
+		let system_time = GetSystemTime();
+		let file_time: *mut FILETIME = std::mem::transmute(std::alloc::alloc(file_time_layout));
+		if SystemTimeToFileTime(&system_time as *const SYSTEMTIME, file_time).is_err() {
+			std::alloc::dealloc(file_time as *mut u8, file_time_layout);
+			return Err(Error::new(ErrorCode::Internal, "failed to read the system time"));
+		}
+		file_time
+	};
+
+	let blob_layout = std::alloc::Layout::new::<[u8; 32]>();
+	// SAFETY: we must free this later, also that these types match
+	let blob = unsafe {
+		let blob: *mut [u8; 32] = std::mem::transmute(std::alloc::alloc_zeroed(blob_layout));
+		(*blob).clone_from(key);
+		blob
+	};
+
+	let comment = CString::new("decryption keys for tpm-ws software backend").unwrap();
+	let username = CString::new("joe").unwrap();
+
+	let cred = CREDENTIALA {
+		Flags: CRED_FLAGS(2),
+		Type: CRED_TYPE(1),
+		TargetName: PSTR::from_raw(cred_name.as_ptr() as *mut u8),
+		Comment: PSTR::from_raw(comment.as_ptr() as *mut u8),
+		LastWritten: unsafe { *file_time }, // SAFETY: `SystemTimeToFileTime` succeeded earlier
+		CredentialBlobSize: 32,
+		CredentialBlob: blob as *mut u8,
+		Persist: CRED_PERSIST(2),
+		AttributeCount: 0,
+		Attributes: std::ptr::null::<CREDENTIAL_ATTRIBUTEA>() as *mut CREDENTIAL_ATTRIBUTEA, // I think windows ignores this for `CRED_TYPE_GENERIC`
+		TargetAlias: PSTR::null(),
+		UserName: PSTR::from_raw(username.as_ptr() as *mut u8)
+	};
+
+	// SAFETY: pray to the windows gods
+	let written = unsafe {
+		CredWriteA(&cred as *const CREDENTIALA, 0)
+	};
+
+	// SAFETY: I think GetSystemTime is infallable?
+	unsafe {
+		std::alloc::dealloc(file_time as *mut u8, file_time_layout);
+		std::alloc::dealloc(blob as *mut u8, blob_layout);
+	}
+
+	written.map_err(|e| Error::new(ErrorCode::KeyringLocked, format!("failed to store credential: {e}")))
+}
+
+// unlike `get_aes_key`, this never creates a key that doesn't exist yet
+#[cfg(target_os = "windows")]
+pub async fn find_aes_key(origin: &str) -> Result<Option<Zeroizing<Vec<u8>>>, Error> {
+	let cred_name = cred_name(origin)?;
+	spawn_blocking(move || read_credential(&cred_name)).await?
+}
+
+#[cfg(target_os = "windows")]
+pub async fn get_aes_key(origin: &str) -> Result<Zeroizing<Vec<u8>>, Error> {
+	let cred_name = cred_name(origin)?;
 
 	spawn_blocking(move || {
-		let layout = std::alloc::Layout::new::<CREDENTIALA>();
+		if let Some(key) = read_credential(&cred_name)? {
+			return Ok(key);
+		}
 
-		// SAFETY: we must free this later
-		let mut cred_ptr = unsafe {
-			std::mem::transmute(std::alloc::alloc(layout))
-		};
-		let double_ptr: *mut *mut CREDENTIALA = &mut cred_ptr;
+		let key = generate_aes_key_blocking();
+		write_credential(&cred_name, &key)?;
+
+		// yes... I know...
+		assert_eq!(key.len(), 32);
+		let mut v = Zeroizing::new(Vec::<u8>::with_capacity(32));
+		v.extend(key.as_ref());
+		Ok(v)
+	}).await?
+}
 
-		// SAFETY: if this is `Err(_)` then `cred_ptr` is probably a biohazard
-		let result = unsafe {
-			CredReadA(PCSTR::from_raw(cred_name.as_ptr() as *const u8), CRED_TYPE(1), 0, double_ptr)
+// see the linux version
+#[cfg(target_os = "windows")]
+pub async fn copy_aes_key(from: &str, to: &str) -> Result<bool, Error> {
+	let (from_name, to_name) = (cred_name(from)?, cred_name(to)?);
+	let to = to.to_string();
+
+	spawn_blocking(move || {
+		let Some(key) = read_credential(&from_name)? else {
+			return Ok(false);
 		};
 
-		if result.is_ok() {
-			let key: [u8; 32] = unsafe {
-				assert_eq!((*cred_ptr).CredentialBlobSize, 32);
-				std::ptr::read_unaligned((*cred_ptr).CredentialBlob as *const [u8; 32])
-			};
-			let key = key.to_vec();
-
-			std::mem::forget(result);
-			// SAFETY: `result` is no longer valid
-			unsafe {
-				CredFree(cred_ptr as *const c_void);
+		match read_credential(&to_name)? {
+			Some(existing) if existing == key => (),
+			Some(_) => return Err(Error::new(ErrorCode::KeyExists, format!("a different aes_key is already stored for {to}"))),
+			None => {
+				let key = <&[u8; 32]>::try_from(key.as_slice())
+					.map_err(|_| Error::new(ErrorCode::Internal, "stored aes key is not 32 bytes"))?;
+				write_credential(&to_name, key)?;
 			}
+		}
 
-			Zeroizing::new(key)
-		} else {
-			std::mem::forget(result);
-			// SAFETY: `result` is no longer valid
-			unsafe {
-				CredFree(cred_ptr as *const c_void);
-			}
+		Ok(true)
+	}).await?
+}
 
-			let key = generate_aes_key_blocking();
-
-			let file_time_layout = std::alloc::Layout::new::<FILETIME>();
-			// SAFETY: we gotta free this
-			let file_time = unsafe {
-				let system_time = GetSystemTime();
-				let file_time: *mut FILETIME = std::mem::transmute(std::alloc::alloc(file_time_layout));
-				SystemTimeToFileTime(&system_time as *const SYSTEMTIME, file_time).unwrap();
-				file_time
-			};
-
-			let blob_layout = std::alloc::Layout::new::<[u8; 32]>();
-			// SAFETY: we must free this later, also that these types match
-			let blob = unsafe {
-				let blob: *mut [u8; 32] = std::mem::transmute(std::alloc::alloc_zeroed(blob_layout));
-				(*blob).clone_from(&key);
-				blob
-			};
-
-			let comment = CString::new("decryption keys for tpm-ws software backend").unwrap();
-			let username = CString::new("joe").unwrap();
-
-			let cred = CREDENTIALA {
-				Flags: CRED_FLAGS(2),
-				Type: CRED_TYPE(1),
-				TargetName: PSTR::from_raw(cred_name.as_ptr() as *mut u8),
-				Comment: PSTR::from_raw(comment.as_ptr() as *mut u8),
-				LastWritten: unsafe { *file_time }, // SAFETY: we unwrapped on `SystemTimeToFileTime` earlier
-				CredentialBlobSize: 32,
-				CredentialBlob: blob as *mut u8,
-				Persist: CRED_PERSIST(2),
-				AttributeCount: 0,
-				Attributes: std::ptr::null::<CREDENTIAL_ATTRIBUTEA>() as *mut CREDENTIAL_ATTRIBUTEA, // I think windows ignores this for `CRED_TYPE_GENERIC`
-				TargetAlias: PSTR::null(),
-				UserName: PSTR::from_raw(username.as_ptr() as *mut u8)
-			};
-
-			// SAFETY: pray to the windows gods
-			unsafe {
-				CredWriteA(&cred as *const CREDENTIALA, 0).unwrap();
-			}
+#[cfg(target_os = "windows")]
+pub async fn delete_aes_key(origin: &str) -> Result<bool, Error> {
+	use windows::core::PCSTR;
+	use windows::Win32::Security::Credentials::{CredDeleteA, CRED_TYPE};
 
-			// SAFETY: I think GetSystemTime is infallable?
-			unsafe {
-				std::alloc::dealloc(file_time as *mut u8, file_time_layout);
-				std::alloc::dealloc(blob as *mut u8, blob_layout);
-			}
+	let cred_name = cred_name(origin)?;
 
-			// yes... I know...
-			assert_eq!(key.len(), 32);
-			let mut v = Zeroizing::new(Vec::<u8>::with_capacity(32));
-			v.extend(key.as_ref());
-			v
+	spawn_blocking(move || {
+		if read_credential(&cred_name)?.is_none() {
+			return Ok(false);
 		}
-	}).await.unwrap()
+
+		// SAFETY: `cred_name` outlives the call
+		unsafe {
+			CredDeleteA(PCSTR::from_raw(cred_name.as_ptr() as *const u8), CRED_TYPE(1), 0)
+		}.map_err(|e| Error::new(ErrorCode::KeyringLocked, format!("failed to delete credential: {e}")))?;
+
+		Ok(true)
+	}).await?
 }
@@ -11,95 +11,211 @@ You should have received a copy of the GNU General Public License along with tpm
 */
 
 use tokio::task::spawn_blocking;
-use tokio_tungstenite::WebSocketStream;
-use tokio_tungstenite::tungstenite::protocol::Message;
-use tokio::io::{AsyncRead, AsyncWrite};
-use futures::sink::SinkExt;
+use tokio::sync::Mutex;
 use p256::ecdsa::{SigningKey, Signature, signature::Signer};
 use rand_core::OsRng;
-use diesel::{QueryDsl, RunQueryDsl, ExpressionMethods};
+use diesel::{Connection, QueryDsl, RunQueryDsl, ExpressionMethods, OptionalExtension, SqliteConnection};
 use zeroize::Zeroizing;
 use aes_gcm::{Aes256Gcm, KeyInit, AeadInPlace, AeadCore};
 use sha3::{Sha3_512, Digest};
-use crate::{db, Backend, SignMsg, SignResp, Resp, EcPoint};
-use crate::secrets::get_aes_key;
+use crate::{db, Backend, BackendType, KeyInfo, SignMode, SignMsg, SignResp, EcPoint};
+use crate::error::{Error, ErrorCode};
+use crate::secrets::{delete_aes_key, find_aes_key, get_aes_key};
+use std::sync::Arc;
 
 #[derive(Default, Debug)]
-pub struct SoftwareBackend;
+pub struct SoftwareBackend {
+	// two first-time requests for the same origin would otherwise both create a key
+	lock: Arc<Mutex<()>>
+}
 
 impl Backend for SoftwareBackend {
 	fn is_supported() -> bool {
 		true
 	}
 
-	async fn sign_msg<S>(&self, ws: &mut WebSocketStream<S>, sign_msg: SignMsg)
-	where
-		S: AsyncRead + AsyncWrite + Unpin
-	{
-		let aes_key = get_aes_key(&sign_msg.origin).await;
-		let sign_resp = spawn_blocking(|| sign(sign_msg, aes_key)).await.unwrap();
-		let msg = rmp_serde::to_vec(&Resp::Sign(sign_resp)).unwrap();
-		ws.send(Message::Binary(msg)).await.unwrap();
+	async fn sign(&self, sign_msg: SignMsg, mode: SignMode) -> Result<SignResp, Error> {
+		let guard = Arc::clone(&self.lock).lock_owned().await;
+		let aes_key = match mode {
+			SignMode::Register => get_aes_key(&sign_msg.origin).await?,
+			SignMode::Authenticate => find_aes_key(&sign_msg.origin).await?
+				.ok_or_else(|| Error::key_not_found(&sign_msg.origin))?
+		};
+		spawn_blocking(move || {
+			let _guard = guard;
+			sign(sign_msg, aes_key, mode)
+		}).await?
+	}
+
+	async fn get_public_key(&self, origin: String) -> Result<EcPoint, Error> {
+		// don't go through `get_aes_key`, that would leave a fresh aes key behind for unknown origins
+		let aes_key = find_aes_key(&origin).await?
+			.ok_or_else(|| Error::key_not_found(&origin))?;
+
+		spawn_blocking(move || {
+			let mut conn = db::get_conn()?;
+			let private_key = find_signing_key(&mut conn, &origin, &aes_key)?
+				.ok_or_else(|| Error::key_not_found(&origin))?;
+			Ok(ec_point(&private_key))
+		}).await?
+	}
+
+	async fn list_keys(&self) -> Result<Vec<KeyInfo>, Error> {
+		use crate::schema::software_keys::dsl;
+
+		let origins: Vec<String> = spawn_blocking(|| {
+			let mut conn = db::get_conn()?;
+			Ok::<_, Error>(dsl::software_keys.select(dsl::origin).order(dsl::origin).load(&mut conn)?)
+		}).await??;
+
+		let mut keys = Vec::with_capacity(origins.len());
+		for origin in origins {
+			// one key with a missing or broken secret shouldn't hide all the others
+			let ec_point = self.get_public_key(origin.clone()).await
+				.inspect_err(|e| log::warn!("failed to read public key for {origin}: {e}"))
+				.ok();
+
+			keys.push(KeyInfo {
+				origin,
+				backend: BackendType::Software,
+				ec_point
+			});
+		}
+
+		Ok(keys)
+	}
+
+	async fn delete_key(&self, origin: String) -> Result<(), Error> {
+		use crate::schema::software_keys::dsl;
+		use crate::models::NewSoftwareKey;
+
+		let _guard = self.lock.lock().await;
+
+		// take the row out first, so it can go back in if the keyring won't let go of the aes key
+		let removed = {
+			let origin = origin.clone();
+			spawn_blocking(move || {
+				let mut conn = db::get_conn()?;
+				conn.transaction(|conn| {
+					let row: Option<(Vec<u8>, Vec<u8>, Vec<u8>)> = dsl::software_keys.filter(dsl::origin.eq(&origin))
+						.select((dsl::encrypted_private_key, dsl::encrypted_private_key_iv, dsl::private_key_sha3_512_sum)).first(conn).optional()?;
+					diesel::delete(dsl::software_keys.filter(dsl::origin.eq(&origin))).execute(conn)?;
+
+					Ok::<_, Error>(row.map(|(encrypted_private_key, encrypted_private_key_iv, private_key_sha3_512_sum)| NewSoftwareKey {
+						origin,
+						encrypted_private_key,
+						encrypted_private_key_iv,
+						private_key_sha3_512_sum
+					}))
+				})
+			}).await??
+		};
+
+		let had_aes_key = match delete_aes_key(&origin).await {
+			Ok(had_aes_key) => had_aes_key,
+			Err(e) => {
+				if let Some(row) = removed {
+					let restored = spawn_blocking(move || {
+						let mut conn = db::get_conn()?;
+						diesel::insert_into(dsl::software_keys).values(row).execute(&mut conn)?;
+						Ok::<_, Error>(())
+					}).await;
+
+					if let Err(e) = restored.map_err(Error::from).and_then(|r| r) {
+						log::error!("failed to restore key for {origin} after a failed delete: {e}");
+					}
+				}
+
+				return Err(e);
+			}
+		};
+
+		if removed.is_none() && !had_aes_key {
+			return Err(Error::key_not_found(&origin));
+		}
+
+		Ok(())
 	}
 }
 
-fn get_signing_key(origin: String, aes_key: &Zeroizing<Vec<u8>>) -> SigningKey {
+fn find_signing_key(conn: &mut SqliteConnection, origin: &str, aes_key: &Zeroizing<Vec<u8>>) -> Result<Option<SigningKey>, Error> {
 	use crate::schema::software_keys::dsl;
-	use crate::models::NewSoftwareKey;
 
-	let mut conn = db::get_conn();
+	let selected: Option<(Vec<u8>, Vec<u8>, Vec<u8>)> = dsl::software_keys.filter(dsl::origin.eq(origin))
+		.select((dsl::encrypted_private_key, dsl::private_key_sha3_512_sum, dsl::encrypted_private_key_iv)).first(conn).optional()?;
 
-	let selected: Result<(Vec<u8>, Vec<u8>, Vec<u8>), _> = dsl::software_keys.filter(dsl::origin.eq(&origin))
-		.select((dsl::encrypted_private_key, dsl::private_key_sha3_512_sum, dsl::encrypted_private_key_iv)).first(&mut conn);
-
-	if let Ok((mut private_key, sha3_512_sum, iv)) = selected {
-		let aes = Aes256Gcm::new_from_slice(aes_key).unwrap();
-		aes.decrypt_in_place(iv.as_slice().into(), &sha3_512_sum, &mut private_key).unwrap();
+	if let Some((mut private_key, sha3_512_sum, iv)) = selected {
+		let private_key_err = || Error::new(ErrorCode::Internal, format!("stored private key for {origin} is corrupt"));
+		let aes = Aes256Gcm::new_from_slice(aes_key).map_err(|_| private_key_err())?;
+		aes.decrypt_in_place(iv.as_slice().into(), &sha3_512_sum, &mut private_key).map_err(|_| private_key_err())?;
 		let hash = Sha3_512::digest(&private_key).to_vec();
-		if hash != sha3_512_sum { panic!("sha3_512 sum doesn't match") }
-		SigningKey::from_bytes(private_key.as_slice().into()).unwrap()
+		if hash != sha3_512_sum { return Err(private_key_err()) }
+		SigningKey::from_bytes(private_key.as_slice().into()).map(Some).map_err(|_| private_key_err())
 	} else {
-		let aes = Aes256Gcm::new(<Zeroizing<Vec<u8>> as AsRef<Vec<u8>>>::as_ref(aes_key).as_slice().into());
-		let private_key = SigningKey::random(&mut OsRng);
-		let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
-		let mut encrypted_private_key = private_key.to_bytes().as_slice().to_vec();
-		let hash = Sha3_512::digest(&encrypted_private_key);
-		aes.encrypt_in_place(&nonce, &hash, &mut encrypted_private_key).unwrap();
-
-		let new_key = NewSoftwareKey {
-			origin,
-			encrypted_private_key,
-			encrypted_private_key_iv: nonce.to_vec(),
-			private_key_sha3_512_sum: hash.to_vec()
-		};
+		Ok(None)
+	}
+}
 
-		diesel::insert_into(dsl::software_keys).values(new_key).execute(&mut conn).unwrap();
+fn get_signing_key(origin: String, aes_key: &Zeroizing<Vec<u8>>, mode: SignMode) -> Result<SigningKey, Error> {
+	use crate::schema::software_keys::dsl;
+	use crate::models::NewSoftwareKey;
 
-		private_key
+	let mut conn = db::get_conn()?;
+
+	let found = find_signing_key(&mut conn, &origin, aes_key)?;
+	match (found, mode) {
+		(Some(private_key), SignMode::Authenticate) => Ok(private_key),
+		(Some(_), SignMode::Register) => Err(Error::key_exists(&origin)),
+		(None, SignMode::Authenticate) => Err(Error::key_not_found(&origin)),
+		(None, SignMode::Register) => {
+			let aes = Aes256Gcm::new_from_slice(aes_key)
+				.map_err(|_| Error::new(ErrorCode::Internal, "keyring returned a malformed aes key"))?;
+			let private_key = SigningKey::random(&mut OsRng);
+			let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+			let mut encrypted_private_key = private_key.to_bytes().as_slice().to_vec();
+			let hash = Sha3_512::digest(&encrypted_private_key);
+			aes.encrypt_in_place(&nonce, &hash, &mut encrypted_private_key)
+				.map_err(|_| Error::new(ErrorCode::Internal, "failed to encrypt private key"))?;
+
+			let new_key = NewSoftwareKey {
+				origin,
+				encrypted_private_key,
+				encrypted_private_key_iv: nonce.to_vec(),
+				private_key_sha3_512_sum: hash.to_vec()
+			};
+
+			diesel::insert_into(dsl::software_keys).values(new_key).execute(&mut conn)?;
+
+			Ok(private_key)
+		}
 	}
 }
 
-fn sign(sign_msg: SignMsg, aes_key: Zeroizing<Vec<u8>>) -> SignResp {
-	let private_key = get_signing_key(sign_msg.origin, &aes_key);
+fn sign(sign_msg: SignMsg, aes_key: Zeroizing<Vec<u8>>, mode: SignMode) -> Result<SignResp, Error> {
+	let private_key = get_signing_key(sign_msg.origin, &aes_key, mode)?;
 	let signature: Signature = private_key.sign(&sign_msg.data);
 	let (r, s) = signature.split_bytes();
 
 	let ec_point = if sign_msg.include_key {
-		let public_key = private_key.verifying_key();
-
-		// this is a bit inefficient, but this library really does not want me to access the raw coordinates...
-		let encoded = public_key.to_sec1_bytes();
-		assert_eq!(encoded.len(), 65);
-		let (x, y) = (encoded[1..33].to_vec(), encoded[33..65].to_vec());
-
-		Some(EcPoint{ x, y })
+		Some(ec_point(&private_key))
 	} else {
 		None
 	};
 
-	SignResp {
+	Ok(SignResp {
 		sig_r: r.to_vec(),
 		sig_s: s.to_vec(),
 		ec_point
-	}
+	})
+}
+
+fn ec_point(private_key: &SigningKey) -> EcPoint {
+	let public_key = private_key.verifying_key();
+
+	// this is a bit inefficient, but this library really does not want me to access the raw coordinates...
+	let encoded = public_key.to_sec1_bytes();
+	assert_eq!(encoded.len(), 65);
+	let (x, y) = (encoded[1..33].to_vec(), encoded[33..65].to_vec());
+
+	EcPoint{ x, y }
 }
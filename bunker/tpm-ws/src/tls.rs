@@ -0,0 +1,277 @@
+/*
+Copyright James Connolly 2024
+
+This file is part of tpm-ws.
+
+tpm-ws is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+
+tpm-ws is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with tpm-ws. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::task::spawn_blocking;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::{self, ServerConfig};
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use rcgen::{BasicConstraints, CertificateParams, CidrSubnet, DnType, ExtendedKeyUsagePurpose, GeneralSubtree, IsCa, KeyPair, KeyUsagePurpose, NameConstraints, SanType, SerialNumber};
+use rand_core::{OsRng, RngCore};
+use diesel::{Connection, QueryDsl, RunQueryDsl, ExpressionMethods, OptionalExtension, SqliteConnection};
+use zeroize::Zeroizing;
+use aes_gcm::{Aes256Gcm, KeyInit, AeadInPlace, AeadCore};
+use sha3::{Sha3_512, Digest};
+use base64::Engine;
+use crate::{config, db, SelectedBackend};
+use crate::error::{Error, ErrorCode};
+use crate::secrets::get_aes_key;
+
+// what the aes key is kept under in the keyring, which no canonical origin can ever be since it has a space in it
+const KEYRING_NAME: &str = "tpm-ws tls";
+
+const CA_LIFETIME: Duration = Duration::from_secs(10 * 365 * 24 * 60 * 60);
+const LEAF_LIFETIME: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+// a leaf closer than this to expiring gets replaced at startup
+const LEAF_RENEW_BEFORE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// a certificate and its private key, as pkcs#8
+struct Issued {
+	certificate: Vec<u8>,
+	private_key: Zeroizing<Vec<u8>>,
+	expires_at: i64
+}
+
+// whatever the browser is pointed at has to be one of these, see `LOOPBACK_HOSTS`
+fn leaf_params() -> Result<CertificateParams, Error> {
+	let mut params = CertificateParams::new(vec!["localhost".to_string()]).map_err(cert_error)?;
+	params.subject_alt_names.push(SanType::IpAddress([127, 0, 0, 1].into()));
+	params.subject_alt_names.push(SanType::IpAddress(std::net::Ipv6Addr::LOCALHOST.into()));
+	params.distinguished_name.push(DnType::CommonName, "localhost");
+	params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+	params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+	Ok(params)
+}
+
+// has to come out the same every time, since leaves get reissued with a ca rebuilt from these and the stored key
+// the name constraints mean that even a stolen ca key could only vouch for loopback, not for anyone's bank
+fn ca_params() -> CertificateParams {
+	let mut params = CertificateParams::default();
+	params.distinguished_name.push(DnType::CommonName, "tpm-ws local CA");
+	params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+	params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+	params.name_constraints = Some(NameConstraints {
+		permitted_subtrees: vec![
+			GeneralSubtree::DnsName("localhost".to_string()),
+			GeneralSubtree::IpAddress(CidrSubnet::from_v4_prefix([127, 0, 0, 0], 8)),
+			GeneralSubtree::IpAddress(CidrSubnet::from_v6_prefix(std::net::Ipv6Addr::LOCALHOST.octets(), 128))
+		],
+		excluded_subtrees: Vec::new()
+	});
+	params
+}
+
+fn cert_error(e: rcgen::Error) -> Error {
+	Error::new(ErrorCode::Internal, format!("failed to make a tls certificate: {e}"))
+}
+
+// fresh serials every time, firefox refuses two certificates from the same issuer with the same one
+fn set_validity(params: &mut CertificateParams, lifetime: Duration) -> i64 {
+	let mut serial = [0u8; 16];
+	OsRng.fill_bytes(&mut serial);
+	// keeps it positive
+	serial[0] &= 0x7f;
+	params.serial_number = Some(SerialNumber::from_slice(&serial));
+
+	let now = time::OffsetDateTime::now_utc();
+	// a little slack for clocks that are slightly behind ours
+	params.not_before = now - time::Duration::hours(1);
+	params.not_after = now + lifetime;
+	params.not_after.unix_timestamp()
+}
+
+fn issue_ca() -> Result<Issued, Error> {
+	let key = KeyPair::generate().map_err(cert_error)?;
+	let mut params = ca_params();
+	let expires_at = set_validity(&mut params, CA_LIFETIME);
+	let certificate = params.self_signed(&key).map_err(cert_error)?;
+
+	Ok(Issued {
+		certificate: certificate.der().to_vec(),
+		private_key: Zeroizing::new(key.serialize_der()),
+		expires_at
+	})
+}
+
+fn issue_leaf(ca: &Issued) -> Result<Issued, Error> {
+	let ca_key = KeyPair::try_from(ca.private_key.as_slice()).map_err(cert_error)?;
+	let ca_certificate = ca_params().self_signed(&ca_key).map_err(cert_error)?;
+
+	let key = KeyPair::generate().map_err(cert_error)?;
+	let mut params = leaf_params()?;
+	let expires_at = set_validity(&mut params, LEAF_LIFETIME).min(ca.expires_at);
+	params.not_after = time::OffsetDateTime::from_unix_timestamp(expires_at).map_err(|e| Error::new(ErrorCode::Internal, format!("bad expiry time: {e}")))?;
+	let certificate = params.signed_by(&key, &ca_certificate, &ca_key).map_err(cert_error)?;
+
+	Ok(Issued {
+		certificate: certificate.der().to_vec(),
+		private_key: Zeroizing::new(key.serialize_der()),
+		expires_at
+	})
+}
+
+fn private_key_err() -> Error {
+	Error::new(ErrorCode::Internal, "stored tls private key is corrupted")
+}
+
+// certificate, encrypted private key, iv, sha3-512 of the private key, expiry
+type Row = (Vec<u8>, Vec<u8>, Vec<u8>, Vec<u8>, i64);
+
+fn find(conn: &mut SqliteConnection, name: &str, aes_key: &Zeroizing<Vec<u8>>) -> Result<Option<Issued>, Error> {
+	use crate::schema::tls_certificates::dsl;
+
+	let selected: Option<Row> = dsl::tls_certificates.filter(dsl::name.eq(name))
+		.select((dsl::certificate, dsl::encrypted_private_key, dsl::encrypted_private_key_iv, dsl::private_key_sha3_512_sum, dsl::expires_at))
+		.first(conn).optional()?;
+
+	let Some((certificate, private_key, iv, sha3_512_sum, expires_at)) = selected else {
+		return Ok(None);
+	};
+
+	let mut private_key = Zeroizing::new(private_key);
+	let aes = Aes256Gcm::new_from_slice(aes_key).map_err(|_| private_key_err())?;
+	aes.decrypt_in_place(iv.as_slice().into(), &sha3_512_sum, &mut *private_key).map_err(|_| private_key_err())?;
+	if Sha3_512::digest(&*private_key).to_vec() != sha3_512_sum {
+		return Err(private_key_err());
+	}
+
+	Ok(Some(Issued { certificate, private_key, expires_at }))
+}
+
+fn store(conn: &mut SqliteConnection, name: &str, issued: &Issued, aes_key: &Zeroizing<Vec<u8>>) -> Result<(), Error> {
+	use crate::schema::tls_certificates::dsl;
+
+	let aes = Aes256Gcm::new_from_slice(aes_key)
+		.map_err(|_| Error::new(ErrorCode::Internal, "keyring returned a malformed aes key"))?;
+	let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+	let mut encrypted_private_key = issued.private_key.to_vec();
+	let hash = Sha3_512::digest(&encrypted_private_key);
+	aes.encrypt_in_place(&nonce, &hash, &mut encrypted_private_key)
+		.map_err(|_| Error::new(ErrorCode::Internal, "failed to encrypt private key"))?;
+
+	let row = crate::models::NewTlsCertificate {
+		name: name.to_string(),
+		certificate: issued.certificate.clone(),
+		encrypted_private_key,
+		encrypted_private_key_iv: nonce.to_vec(),
+		private_key_sha3_512_sum: hash.to_vec(),
+		expires_at: issued.expires_at,
+		created_at: db::now()
+	};
+
+	diesel::insert_into(dsl::tls_certificates).values(&row)
+		.on_conflict(dsl::name).do_update().set(&row)
+		.execute(conn)?;
+	Ok(())
+}
+
+// the ca and a leaf that's good for a while yet, making whichever of them is missing
+async fn load() -> Result<(Issued, Issued), Error> {
+	let aes_key = get_aes_key(KEYRING_NAME).await?;
+
+	spawn_blocking(move || {
+		let mut conn = db::get_conn()?;
+		conn.transaction(|conn| {
+			let ca = match find(conn, "ca", &aes_key)? {
+				Some(ca) => ca,
+				None => {
+					log::info!("making a new local ca, export it with --export-ca to trust it");
+					let ca = issue_ca()?;
+					store(conn, "ca", &ca, &aes_key)?;
+					ca
+				}
+			};
+
+			let renew_after = db::now() + LEAF_RENEW_BEFORE.as_secs() as i64;
+			let leaf = match find(conn, "leaf", &aes_key)? {
+				Some(leaf) if leaf.expires_at > renew_after => leaf,
+				_ => {
+					log::info!("issuing a new localhost certificate");
+					let leaf = issue_leaf(&ca)?;
+					store(conn, "leaf", &leaf, &aes_key)?;
+					leaf
+				}
+			};
+
+			Ok((ca, leaf))
+		})
+	}).await?
+}
+
+// panics if the certificates can't be loaded, since there'd be nothing to serve wss:// with
+async fn acceptor() -> TlsAcceptor {
+	let (ca, leaf) = load().await.unwrap_or_else(|e| panic!("failed to load tls certificates: {e}"));
+
+	let provider = Arc::new(rustls::crypto::ring::default_provider());
+	let chain = vec![CertificateDer::from(leaf.certificate), CertificateDer::from(ca.certificate)];
+	let private_key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(leaf.private_key.to_vec()));
+
+	let config = ServerConfig::builder_with_provider(provider)
+		.with_safe_default_protocol_versions()
+		.and_then(|builder| builder.with_no_client_auth().with_single_cert(chain, private_key))
+		.unwrap_or_else(|e| panic!("failed to set up tls: {e}"));
+
+	TlsAcceptor::from(Arc::new(config))
+}
+
+pub struct Listener {
+	listener: TcpListener,
+	acceptor: TlsAcceptor,
+	port: u16
+}
+
+// `None` if the config doesn't ask for tls
+pub async fn bind() -> Option<Listener> {
+	let port = config::get().tls.port?;
+	let acceptor = acceptor().await;
+	let listener = TcpListener::bind(("127.0.0.1", port)).await
+		.unwrap_or_else(|e| panic!("failed to listen on port {port}: {e}"));
+
+	log::info!("listening for wss:// on port {port}");
+	Some(Listener { listener, acceptor, port })
+}
+
+pub async fn serve(listener: Listener, selected_backend: Arc<SelectedBackend>) {
+	let Listener { listener, acceptor, port } = listener;
+
+	while let Ok((stream, addr)) = listener.accept().await {
+		log::debug!("accepted tls connection from {addr}");
+		let (acceptor, selected_backend) = (acceptor.clone(), Arc::clone(&selected_backend));
+
+		// the handshake happens in its own task, so a client that never finishes it holds up nobody else
+		tokio::spawn(async move {
+			match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+				Ok(Ok(stream)) => crate::handle_connection(stream, selected_backend, None, Some(port)).await,
+				Ok(Err(e)) => log::error!("tls handshake failed: {e}"),
+				Err(_) => log::error!("tls handshake timed out")
+			}
+		});
+	}
+}
+
+// as pem, for adding to the browser's or system's trusted roots
+pub async fn export_ca() -> String {
+	let (ca, _) = load().await.unwrap_or_else(|e| panic!("failed to load tls certificates: {e}"));
+
+	let encoded = base64::engine::general_purpose::STANDARD.encode(&ca.certificate);
+	let mut pem = String::from("-----BEGIN CERTIFICATE-----\n");
+	for line in encoded.as_bytes().chunks(64) {
+		pem += std::str::from_utf8(line).unwrap();
+		pem += "\n";
+	}
+	pem += "-----END CERTIFICATE-----\n";
+	pem
+}
@@ -10,11 +10,8 @@ tpm-ws is distributed in the hope that it will be useful, but WITHOUT ANY WARRAN
 You should have received a copy of the GNU General Public License along with tpm-ws. If not, see <https://www.gnu.org/licenses/>.
 */
 
-use futures::sink::SinkExt;
 use tokio::task::spawn_blocking;
-use tokio::io::{AsyncRead, AsyncWrite};
-use tokio_tungstenite::WebSocketStream;
-use tokio_tungstenite::tungstenite::protocol::Message;
+use tokio::sync::Mutex;
 use tss_esapi::Context;
 use tss_esapi::tcti_ldr::TctiNameConf;
 use tss_esapi::structures::{CreatePrimaryKeyResult, Digest, PublicBuilder, SymmetricCipherParameters, SymmetricDefinitionObject, PublicEccParametersBuilder, SignatureScheme, HashScheme, EccScheme, KeyDerivationFunctionScheme, EccPoint, Signature, Public, Private, Auth};
@@ -27,13 +24,34 @@ use tss_esapi::interface_types::{
 };
 use tss_esapi::traits::{Marshall, UnMarshall};
 use std::path::Path;
-use diesel::{QueryDsl, RunQueryDsl, ExpressionMethods};
+use std::sync::Arc;
+use diesel::{Connection, QueryDsl, RunQueryDsl, ExpressionMethods, OptionalExtension};
 use zeroize::Zeroizing;
-use crate::{db, Backend, Resp, SignMsg, SignResp, EcPoint};
-use crate::secrets::get_password;
+use crate::{db, Backend, BackendType, KeyInfo, SignMode, SignMsg, SignResp, EcPoint};
+use crate::error::{Error, ErrorCode};
+use crate::secrets::{delete_password, find_password, get_password};
+
+// TPM_RC_LOCKOUT, the dictionary attack lockout warning
+const TPM2_RC_LOCKOUT: u32 = 0x921;
+
+impl From<tss_esapi::Error> for Error {
+	fn from(e: tss_esapi::Error) -> Self {
+		if let tss_esapi::Error::TssError(rc) = &e {
+			// mask off the layer bits, we only care about what the tpm itself said
+			if u32::from(*rc) & 0xffff == TPM2_RC_LOCKOUT {
+				return Error::new(ErrorCode::TpmLockout, "tpm is in dictionary attack lockout");
+			}
+		}
+
+		Error::new(ErrorCode::Internal, format!("tpm error: {e}"))
+	}
+}
 
 #[derive(Default, Debug)]
-pub struct TpmBackend;
+pub struct TpmBackend {
+	// the tpm can only juggle a handful of loaded objects, so only one request talks to it at a time
+	lock: Arc<Mutex<()>>
+}
 
 impl Backend for TpmBackend {
 	// not perfect, but it'll do
@@ -41,18 +59,106 @@ impl Backend for TpmBackend {
 		Path::new("/dev/tpm0").exists()
 	}
 
-	async fn sign_msg<S>(&self, ws: &mut WebSocketStream<S>, sign_msg: SignMsg)
-	where
-		S: AsyncRead + AsyncWrite + Unpin
-	{
-		let password = get_password(&sign_msg.origin).await;
-		let sign_resp = spawn_blocking(|| sign(sign_msg, password)).await.unwrap();
-		let msg = rmp_serde::to_vec(&Resp::Sign(sign_resp)).unwrap();
-		ws.send(Message::Binary(msg)).await.unwrap();
+	async fn sign(&self, sign_msg: SignMsg, mode: SignMode) -> Result<SignResp, Error> {
+		let guard = Arc::clone(&self.lock).lock_owned().await;
+		let password = match mode {
+			SignMode::Register => get_password(&sign_msg.origin).await?,
+			SignMode::Authenticate => find_password(&sign_msg.origin).await?
+				.ok_or_else(|| Error::key_not_found(&sign_msg.origin))?
+		};
+		spawn_blocking(move || {
+			let _guard = guard;
+			sign(sign_msg, password, mode)
+		}).await?
+	}
+
+	// the public half lives in the database, so this never has to touch the tpm
+	async fn get_public_key(&self, origin: String) -> Result<EcPoint, Error> {
+		use crate::schema::tpm_keys::dsl;
+
+		spawn_blocking(move || {
+			let mut conn = db::get_conn()?;
+			let public_key: Vec<u8> = dsl::tpm_keys.filter(dsl::origin.eq(&origin)).select(dsl::public_key).first(&mut conn).optional()?
+				.ok_or_else(|| Error::key_not_found(&origin))?;
+			ec_point(&Public::unmarshall(&public_key)?)
+		}).await?
+	}
+
+	async fn list_keys(&self) -> Result<Vec<KeyInfo>, Error> {
+		use crate::schema::tpm_keys::dsl;
+
+		spawn_blocking(|| {
+			let mut conn = db::get_conn()?;
+			let keys: Vec<(String, Vec<u8>)> = dsl::tpm_keys.select((dsl::origin, dsl::public_key)).order(dsl::origin).load(&mut conn)?;
+
+			Ok(keys.into_iter().map(|(origin, public_key)| {
+				let ec_point = Public::unmarshall(&public_key).map_err(Error::from)
+					.and_then(|public| ec_point(&public))
+					.inspect_err(|e| log::warn!("failed to read public key for {origin}: {e}"))
+					.ok();
+
+				KeyInfo {
+					origin,
+					backend: BackendType::Tpm,
+					ec_point
+				}
+			}).collect())
+		}).await?
+	}
+
+	async fn delete_key(&self, origin: String) -> Result<(), Error> {
+		use crate::schema::tpm_keys::dsl;
+		use crate::models::NewTpmKeyPair;
+
+		let _guard = self.lock.lock().await;
+
+		// take the row out first, so it can go back in if the keyring won't let go of the password
+		let removed = {
+			let origin = origin.clone();
+			spawn_blocking(move || {
+				let mut conn = db::get_conn()?;
+				conn.transaction(|conn| {
+					let row: Option<(Vec<u8>, Vec<u8>)> = dsl::tpm_keys.filter(dsl::origin.eq(&origin))
+						.select((dsl::sealed_private_key, dsl::public_key)).first(conn).optional()?;
+					diesel::delete(dsl::tpm_keys.filter(dsl::origin.eq(&origin))).execute(conn)?;
+
+					Ok::<_, Error>(row.map(|(sealed_private_key, public_key)| NewTpmKeyPair {
+						origin,
+						sealed_private_key,
+						public_key
+					}))
+				})
+			}).await??
+		};
+
+		let had_password = match delete_password(&origin).await {
+			Ok(had_password) => had_password,
+			Err(e) => {
+				if let Some(row) = removed {
+					let restored = spawn_blocking(move || {
+						let mut conn = db::get_conn()?;
+						diesel::insert_into(dsl::tpm_keys).values(row).execute(&mut conn)?;
+						Ok::<_, Error>(())
+					}).await;
+
+					if let Err(e) = restored.map_err(Error::from).and_then(|r| r) {
+						log::error!("failed to restore key for {origin} after a failed delete: {e}");
+					}
+				}
+
+				return Err(e);
+			}
+		};
+
+		if removed.is_none() && !had_password {
+			return Err(Error::key_not_found(&origin));
+		}
+
+		Ok(())
 	}
 }
 
-fn create_primary(tpm: &mut Context, password: &Zeroizing<Vec<u8>>) -> CreatePrimaryKeyResult {
+fn create_primary(tpm: &mut Context, password: &Zeroizing<Vec<u8>>) -> Result<CreatePrimaryKeyResult, Error> {
 	let attrs = ObjectAttributesBuilder::new()
 		.with_fixed_tpm(true)
 		.with_fixed_parent(true)
@@ -61,7 +167,7 @@ fn create_primary(tpm: &mut Context, password: &Zeroizing<Vec<u8>>) -> CreatePri
 		.with_user_with_auth(true)
 		.with_decrypt(true)
 		.with_restricted(true)
-		.build().unwrap();
+		.build()?;
 
 	let primary_pub = PublicBuilder::new()
 		.with_public_algorithm(PublicAlgorithm::SymCipher)
@@ -71,42 +177,47 @@ fn create_primary(tpm: &mut Context, password: &Zeroizing<Vec<u8>>) -> CreatePri
 			SymmetricDefinitionObject::AES_256_CFB
 		))
 		.with_symmetric_cipher_unique_identifier(Digest::default())
-		.build().unwrap();
+		.build()?;
 
-	tpm.execute_with_session(Some(AuthSession::Password), |ctx| {
-		let auth_value = Auth::try_from(password.as_ref()).unwrap();
+	Ok(tpm.execute_with_session(Some(AuthSession::Password), |ctx| {
+		let auth_value = Auth::try_from(password.as_ref())?;
 		ctx.create_primary(Hierarchy::Owner, primary_pub, Some(auth_value), None, None, None)
-	}).unwrap()
+	})?)
 }
 
-fn get_keypair(tpm: &mut Context, primary: &CreatePrimaryKeyResult, password: &Zeroizing<Vec<u8>>, origin: String) -> (Private, Public) {
+fn get_keypair(tpm: &mut Context, primary: &CreatePrimaryKeyResult, password: &Zeroizing<Vec<u8>>, origin: String, mode: SignMode) -> Result<(Private, Public), Error> {
 	use crate::schema::tpm_keys::dsl;
 	use crate::models::NewTpmKeyPair;
 
-	let mut conn = db::get_conn();
-
-	let selected: Result<(Vec<u8>, Vec<u8>), _> = dsl::tpm_keys.filter(dsl::origin.eq(&origin)).select((dsl::sealed_private_key, dsl::public_key)).first(&mut conn);
-
-	if let Ok((sealed_private_key, public_key)) = selected {
-		let private = sealed_private_key.try_into().unwrap();
-		let public = Public::unmarshall(&public_key).unwrap();
-		(private, public)
-	} else {
-		let (private, public) = generate_keypair(tpm, primary, password);
-
-		let pair = NewTpmKeyPair {
-			origin,
-			sealed_private_key: private.to_vec(),
-			public_key: public.marshall().unwrap()
-		};
+	let mut conn = db::get_conn()?;
+
+	let selected: Option<(Vec<u8>, Vec<u8>)> = dsl::tpm_keys.filter(dsl::origin.eq(&origin)).select((dsl::sealed_private_key, dsl::public_key)).first(&mut conn).optional()?;
+
+	match (selected, mode) {
+		(Some((sealed_private_key, public_key)), SignMode::Authenticate) => {
+			let private = sealed_private_key.try_into()?;
+			let public = Public::unmarshall(&public_key)?;
+			Ok((private, public))
+		},
+		(Some(_), SignMode::Register) => Err(Error::key_exists(&origin)),
+		(None, SignMode::Authenticate) => Err(Error::key_not_found(&origin)),
+		(None, SignMode::Register) => {
+			let (private, public) = generate_keypair(tpm, primary, password)?;
+
+			let pair = NewTpmKeyPair {
+				origin,
+				sealed_private_key: private.to_vec(),
+				public_key: public.marshall()?
+			};
 
-		diesel::insert_into(dsl::tpm_keys).values(pair).execute(&mut conn).unwrap();
+			diesel::insert_into(dsl::tpm_keys).values(pair).execute(&mut conn)?;
 
-		(private, public)
+			Ok((private, public))
+		}
 	}
 }
 
-fn generate_keypair(tpm: &mut Context, primary: &CreatePrimaryKeyResult, password: &Zeroizing<Vec<u8>>) -> (Private, Public) {
+fn generate_keypair(tpm: &mut Context, primary: &CreatePrimaryKeyResult, password: &Zeroizing<Vec<u8>>) -> Result<(Private, Public), Error> {
 	log::debug!("generating new keypair");
 
 	let attrs = ObjectAttributesBuilder::new()
@@ -117,7 +228,7 @@ fn generate_keypair(tpm: &mut Context, primary: &CreatePrimaryKeyResult, passwor
 		.with_user_with_auth(true)
 		.with_decrypt(false)
 		.with_sign_encrypt(true)
-		.build().unwrap();
+		.build()?;
 
 	let ecc_params = PublicEccParametersBuilder::new()
 		.with_curve(EccCurve::NistP256)
@@ -126,7 +237,7 @@ fn generate_keypair(tpm: &mut Context, primary: &CreatePrimaryKeyResult, passwor
 		.with_is_signing_key(true)
 		.with_is_decryption_key(false)
 		.with_restricted(true)
-		.build().unwrap();
+		.build()?;
 
 	let key_pub = PublicBuilder::new()
 		.with_public_algorithm(PublicAlgorithm::Ecc)
@@ -134,58 +245,66 @@ fn generate_keypair(tpm: &mut Context, primary: &CreatePrimaryKeyResult, passwor
 		.with_object_attributes(attrs)
 		.with_ecc_parameters(ecc_params)
 		.with_ecc_unique_identifier(EccPoint::default())
-		.build().unwrap();
+		.build()?;
 
-	tpm.execute_with_session(Some(AuthSession::Password), |ctx| {
-		let auth_value = Auth::try_from(password.as_ref()).unwrap();
+	Ok(tpm.execute_with_session(Some(AuthSession::Password), |ctx| {
+		let auth_value = Auth::try_from(password.as_ref())?;
 		ctx.create(primary.key_handle, key_pub, Some(auth_value), None, None, None).map(|k| (k.out_private, k.out_public))
-	}).unwrap()
+	})?)
 }
 
-fn sign(sign_msg: SignMsg, password: Zeroizing<Vec<u8>>) -> SignResp {
-	let mut tpm = Context::new(
-		TctiNameConf::from_environment_variable().unwrap()
-	).unwrap();
+fn sign(sign_msg: SignMsg, password: Zeroizing<Vec<u8>>, mode: SignMode) -> Result<SignResp, Error> {
+	let tcti = TctiNameConf::from_environment_variable()
+		.map_err(|e| Error::new(ErrorCode::BackendUnavailable, format!("failed to find tpm: {e}")))?;
+	let mut tpm = Context::new(tcti)
+		.map_err(|e| Error::new(ErrorCode::BackendUnavailable, format!("failed to open tpm: {e}")))?;
 
-	let primary = create_primary(&mut tpm, &password);
+	let primary = create_primary(&mut tpm, &password)?;
 
-	let (sealed_private, public) = get_keypair(&mut tpm, &primary, &password, sign_msg.origin);
+	let (sealed_private, public) = get_keypair(&mut tpm, &primary, &password, sign_msg.origin, mode)?;
 
+	let data = sign_msg.data.try_into()
+		.map_err(|_| Error::new(ErrorCode::MalformedRequest, "data is too long to be signed by the tpm"))?;
 	let (hash, ticket) = tpm.execute_with_nullauth_session(|ctx| {
-		ctx.hash(sign_msg.data.try_into().unwrap(), HashingAlgorithm::Sha256, Hierarchy::Owner)
-	}).unwrap();
+		ctx.hash(data, HashingAlgorithm::Sha256, Hierarchy::Owner)
+	})?;
 
 	let signed = tpm.execute_with_session(Some(AuthSession::Password), |ctx| {
-		let private = ctx.load(primary.key_handle, sealed_private, public.clone()).unwrap();
-		let auth_value = Auth::try_from(password.as_ref()).unwrap();
-		ctx.tr_set_auth(private.into(), auth_value).unwrap();
+		let private = ctx.load(primary.key_handle, sealed_private, public.clone())?;
+		let auth_value = Auth::try_from(password.as_ref())?;
+		ctx.tr_set_auth(private.into(), auth_value)?;
 		ctx.sign(private, hash, SignatureScheme::EcDsa {
 			hash_scheme: HashScheme::new(HashingAlgorithm::Sha256)
 		}, ticket)
-	}).unwrap();
+	})?;
 
 	if let Signature::EcDsa(sig) = signed {
 		let sig_r = sig.signature_r().value().to_vec();
 		let sig_s = sig.signature_s().value().to_vec();
 
 		let ec_point = if sign_msg.include_key {
-			let (x, y) = if let Public::Ecc { unique, .. } = public {
-				(unique.x().value().to_vec(), unique.y().value().to_vec())
-			} else {
-				unreachable!("should be ecc public key")
-			};
-
-			Some(EcPoint { x, y })
+			Some(ec_point(&public)?)
 		} else {
 			None
 		};
 
-		SignResp {
+		Ok(SignResp {
 			sig_r,
 			sig_s,
 			ec_point
-		}
+		})
+	} else {
+		Err(Error::new(ErrorCode::Internal, "tpm returned a non-ecdsa signature"))
+	}
+}
+
+fn ec_point(public: &Public) -> Result<EcPoint, Error> {
+	if let Public::Ecc { unique, .. } = public {
+		Ok(EcPoint {
+			x: unique.x().value().to_vec(),
+			y: unique.y().value().to_vec()
+		})
 	} else {
-		unreachable!("should be ecdsa signature")
+		Err(Error::new(ErrorCode::Internal, "stored public key is not an ecc key"))
 	}
 }
@@ -0,0 +1,127 @@
+/*
+Copyright James Connolly 2024
+
+This file is part of tpm-ws.
+
+tpm-ws is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+
+tpm-ws is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with tpm-ws. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use serde::Serialize;
+use std::path::PathBuf;
+
+// what the kernel told us about the other end of a unix socket connection
+#[derive(Serialize, Debug, Clone)]
+pub struct Peer {
+	pub uid: u32,
+	pub pid: Option<i32>,
+	// only on linux, and only while the process is still around to ask
+	pub exe: Option<PathBuf>
+}
+
+impl std::fmt::Display for Peer {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		// paths can have anything in them, so escape it
+		match &self.exe {
+			Some(exe) => write!(f, "{exe:?}")?,
+			None => write!(f, "a local app")?
+		}
+
+		match self.pid {
+			Some(pid) => write!(f, " (pid {pid}, uid {})", self.uid),
+			None => write!(f, " (uid {})", self.uid)
+		}
+	}
+}
+
+#[cfg(unix)]
+pub use listener::{bind, serve};
+
+#[cfg(unix)]
+mod listener {
+	use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+	use std::path::PathBuf;
+	use std::sync::Arc;
+	use tokio::net::{UnixListener, UnixStream};
+	use crate::config;
+	use crate::SelectedBackend;
+	use super::Peer;
+
+	pub struct Socket {
+		listener: UnixListener,
+		uids: Vec<u32>
+	}
+
+	// `None` if the config doesn't ask for one
+	pub fn bind() -> Option<Socket> {
+		let config = &config::get().unix_socket;
+		let path = config.path.as_ref()?;
+
+		// a socket left behind by an earlier run would make binding fail, anything else there isn't ours to delete
+		if let Ok(metadata) = std::fs::symlink_metadata(path) {
+			if !metadata.file_type().is_socket() {
+				panic!("{} exists and isn't a socket", path.display());
+			}
+			std::fs::remove_file(path).unwrap_or_else(|e| panic!("failed to remove old socket {}: {e}", path.display()));
+		}
+
+		let listener = UnixListener::bind(path).unwrap_or_else(|e| panic!("failed to listen on {}: {e}", path.display()));
+		std::fs::set_permissions(path, std::fs::Permissions::from_mode(config.mode))
+			.unwrap_or_else(|e| panic!("failed to set permissions on {}: {e}", path.display()));
+
+		// whoever owns the socket we just made is who we run as
+		let own_uid = std::fs::metadata(path).map(|metadata| metadata.uid())
+			.unwrap_or_else(|e| panic!("failed to stat {}: {e}", path.display()));
+		let uids = config.uids.clone().unwrap_or_else(|| vec![own_uid]);
+
+		log::info!("listening on {}", path.display());
+		Some(Socket { listener, uids })
+	}
+
+	pub async fn serve(socket: Socket, selected_backend: Arc<SelectedBackend>) {
+		let Socket { listener, uids } = socket;
+
+		while let Ok((stream, _)) = listener.accept().await {
+			let Some(peer) = peer(&stream) else { continue };
+
+			// the file mode should already keep everyone else out, this is in case it's looser than it should be
+			if !uids.contains(&peer.uid) {
+				log::error!("refusing {peer}, uid {} isn't allowed", peer.uid);
+				continue;
+			}
+
+			log::debug!("accepted connection from {peer}");
+			tokio::spawn(crate::handle_connection(stream, Arc::clone(&selected_backend), Some(peer), None));
+		}
+	}
+
+	fn peer(stream: &UnixStream) -> Option<Peer> {
+		let cred = match stream.peer_cred() {
+			Ok(cred) => cred,
+			Err(e) => {
+				log::error!("failed to get peer credentials: {e}");
+				return None;
+			}
+		};
+
+		Some(Peer {
+			uid: cred.uid(),
+			pid: cred.pid(),
+			exe: cred.pid().and_then(exe)
+		})
+	}
+
+	// the pid could be reused by the time we look, so this is for showing the user, not proof of anything
+	#[cfg(target_os = "linux")]
+	fn exe(pid: i32) -> Option<PathBuf> {
+		std::fs::read_link(format!("/proc/{pid}/exe")).ok()
+	}
+
+	#[cfg(not(target_os = "linux"))]
+	fn exe(_pid: i32) -> Option<PathBuf> {
+		None
+	}
+}